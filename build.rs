@@ -89,6 +89,10 @@ fn main() {
         #[cfg(target_os = "macos")]
         build_mac();
         println!("cargo:rustc-link-lib=framework=ApplicationServices");
+        println!("cargo:rustc-link-lib=framework=CoreMedia");
+        println!("cargo:rustc-link-lib=framework=Metal");
+        // ScreenCaptureKit is only available on macOS >= 12.3.
+        println!("cargo:rustc-link-arg=-Wl,-weak_framework,ScreenCaptureKit");
     }
     println!("cargo:rerun-if-changed=build.rs");
 }
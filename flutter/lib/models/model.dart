@@ -2038,12 +2038,32 @@ class CursorModel with ChangeNotifier {
 
   updateCursorData(Map<String, dynamic> evt) async {
     final id = evt['id'];
-    final hotx = double.parse(evt['hotx']);
-    final hoty = double.parse(evt['hoty']);
-    final width = int.parse(evt['width']);
-    final height = int.parse(evt['height']);
+    var hotx = double.parse(evt['hotx']);
+    var hoty = double.parse(evt['hoty']);
+    var width = int.parse(evt['width']);
+    var height = int.parse(evt['height']);
     List<dynamic> colors = json.decode(evt['colors']);
-    final rgba = Uint8List.fromList(colors.map((s) => s as int).toList());
+    var rgba = Uint8List.fromList(colors.map((s) => s as int).toList());
+    // The size and the hotspot are in pixels, e.g. twice the points for Retina cursors.
+    final scale = double.tryParse(evt['scale'] ?? '') ?? 1.0;
+    if (scale > 1.0) {
+      final logicalWidth = max(1, (width / scale).round());
+      final logicalHeight = max(1, (height / scale).round());
+      final resized = img2.copyResize(
+          img2.Image.fromBytes(
+              width: width,
+              height: height,
+              bytes: rgba.buffer,
+              order: img2.ChannelOrder.rgba),
+          width: logicalWidth,
+          height: logicalHeight,
+          interpolation: img2.Interpolation.average);
+      rgba = resized.getBytes(order: img2.ChannelOrder.rgba);
+      width = logicalWidth;
+      height = logicalHeight;
+      hotx /= scale;
+      hoty /= scale;
+    }
     final image = await img.decodeImageFromPixels(
         rgba, width, height, ui.PixelFormat.rgba8888);
     if (image == null) {
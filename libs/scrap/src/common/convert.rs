@@ -191,6 +191,59 @@ pub fn convert_to_yuv(
                 src_height as _,
             ));
         }
+        (crate::Pixfmt::NV12, crate::Pixfmt::NV12) | (crate::Pixfmt::NV12, crate::Pixfmt::I420) => {
+            // Y plane followed by the interleaved UV plane, e.g. a 420v surface of macOS.
+            let uv_height = (src_height + 1) / 2;
+            if src_stride.len() < 2
+                || src.len() < src_stride[0] * src_height + src_stride[1] * uv_height
+            {
+                bail!(
+                    "wrong nv12 src, len: {}, stride: {:?}",
+                    src.len(),
+                    src_stride
+                );
+            }
+            let src_uv = src[src_stride[0] * src_height..].as_ptr();
+            let dst_stride_y = dst_fmt.stride[0];
+            let dst_stride_uv = dst_fmt.stride[1];
+            if dst_fmt.pixfmt == crate::Pixfmt::NV12 {
+                dst.resize(
+                    align(dst_fmt.h) * (align(dst_stride_y) + align(dst_stride_uv / 2)),
+                    0,
+                );
+                for y in 0..src_height {
+                    let s = y * src_stride[0];
+                    let d = y * dst_stride_y;
+                    dst[d..d + src_width].copy_from_slice(&src[s..s + src_width]);
+                }
+                let uv_width = (src_width + 1) / 2 * 2;
+                let src_uv_offset = src_stride[0] * src_height;
+                for y in 0..uv_height {
+                    let s = src_uv_offset + y * src_stride[1];
+                    let d = dst_fmt.u + y * dst_stride_uv;
+                    dst[d..d + uv_width].copy_from_slice(&src[s..s + uv_width]);
+                }
+            } else {
+                dst.resize(dst_fmt.h * dst_stride_y * 2, 0); // waste some memory to ensure memory safety
+                let dst_y = dst.as_mut_ptr();
+                let dst_u = dst[dst_fmt.u..].as_mut_ptr();
+                let dst_v = dst[dst_fmt.v..].as_mut_ptr();
+                call_yuv!(NV12ToI420(
+                    src.as_ptr(),
+                    src_stride[0] as _,
+                    src_uv,
+                    src_stride[1] as _,
+                    dst_y,
+                    dst_stride_y as _,
+                    dst_u,
+                    dst_stride_uv as _,
+                    dst_v,
+                    dst_stride_uv as _,
+                    src_width as _,
+                    src_height as _,
+                ));
+            }
+        }
         _ => {
             bail!(unsupported);
         }
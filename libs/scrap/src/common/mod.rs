@@ -141,6 +141,15 @@ impl Default for AdapterDevice {
     }
 }
 
+/// A changed region of a captured frame, in display pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DirtyRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
 pub trait TraitPixelBuffer {
     fn data(&self) -> &[u8];
 
@@ -151,6 +160,11 @@ pub trait TraitPixelBuffer {
     fn stride(&self) -> Vec<usize>;
 
     fn pixfmt(&self) -> Pixfmt;
+
+    /// The regions changed since the previous frame, empty if the backend can't supply them.
+    fn dirty_rects(&self) -> &[DirtyRect] {
+        &[]
+    }
 }
 
 #[cfg(not(any(target_os = "ios")))]
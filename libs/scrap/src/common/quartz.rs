@@ -1,38 +1,153 @@
 use crate::{quartz, Frame, Pixfmt};
 use std::marker::PhantomData;
 use std::sync::{Arc, Mutex, TryLockError};
+use std::time::Instant;
 use std::{io, mem};
 
+pub use quartz::PixelFormat;
+
+// The stream is restarted at most this many times in a row before giving up.
+const MAX_CAPTURE_RESTARTS: u32 = 3;
+const CAPTURE_RESTART_DELAY: std::time::Duration = std::time::Duration::from_millis(300);
+
+static CAPTURE_PIXEL_FORMAT: Mutex<PixelFormat> = Mutex::new(PixelFormat::Argb8888);
+
+/// The default pixel format of capturers created afterwards by `Capturer::new()`.
+/// `YCbCr420Video` lets hardware encoders take NV12 without a BGRA to YUV conversion,
+/// other formats fall back to `Argb8888`. NV12 frames can only be encoded as NV12 or I420, not I444.
+pub fn set_capture_pixel_format(format: PixelFormat) {
+    let format = match format {
+        PixelFormat::Argb8888 | PixelFormat::YCbCr420Video => format,
+        _ => {
+            log::info!(
+                "Capture pixel format {:?} is not supported, fall back to BGRA",
+                format
+            );
+            PixelFormat::Argb8888
+        }
+    };
+    *CAPTURE_PIXEL_FORMAT.lock().unwrap() = format;
+}
+
+pub fn capture_pixel_format() -> PixelFormat {
+    *CAPTURE_PIXEL_FORMAT.lock().unwrap()
+}
+
 pub struct Capturer {
     inner: quartz::Capturer,
+    // The display id may change after a reconfiguration, the UUID does not.
+    uuid: Option<[u8; 16]>,
     frame: Arc<Mutex<Option<quartz::Frame>>>,
     saved_raw_data: Vec<u8>, // for faster compare and copy
+    restarts: u32,
+    restart_at: Option<Instant>,
+    crop_top: usize,
 }
 
 impl Capturer {
     pub fn new(display: Display) -> io::Result<Capturer> {
+        Self::new_with_format(display, capture_pixel_format())
+    }
+
+    /// Ignores `set_capture_pixel_format()`, e.g. BGRA for a screenshot while the sessions capture NV12.
+    pub fn new_with_format(display: Display, format: PixelFormat) -> io::Result<Capturer> {
         let frame = Arc::new(Mutex::new(None));
+        let inner = Self::start(display.0, format, frame.clone())?;
+        Ok(Capturer {
+            crop_top: display.crop_top(),
+            inner,
+            uuid: display.0.uuid(),
+            frame,
+            saved_raw_data: Vec::new(),
+            restarts: 0,
+            restart_at: None,
+        })
+    }
 
-        let f = frame.clone();
-        let inner = quartz::Capturer::new(
-            display.0,
+    fn start(
+        display: quartz::Display,
+        format: PixelFormat,
+        frame: Arc<Mutex<Option<quartz::Frame>>>,
+    ) -> io::Result<quartz::Capturer> {
+        quartz::Capturer::new(
+            display,
             display.width(),
             display.height(),
-            quartz::PixelFormat::Argb8888,
+            format,
             Default::default(),
-            move |inner| {
-                if let Ok(mut f) = f.lock() {
+            move |mut inner| {
+                if let Ok(mut f) = frame.lock() {
+                    if let Some(previous) = f.take() {
+                        inner.merge_dirty_rects(&previous);
+                    }
                     *f = Some(inner);
                 }
             },
         )
-        .map_err(|_| io::Error::from(io::ErrorKind::Other))?;
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+    }
 
-        Ok(Capturer {
-            inner,
-            frame,
-            saved_raw_data: Vec::new(),
-        })
+    // The stream may be stopped by the system mid-session, e.g. display reconfiguration or permission revocation.
+    // Re-establish it instead of freezing the remote view.
+    // The first call schedules the restart and returns `Interrupted`, so that the caller can tell the peer
+    // capture is reconnecting. It returns `WouldBlock` until the restart is done, without blocking `frame()`.
+    fn try_restart(&mut self) -> io::Result<()> {
+        let Some(restart_at) = self.restart_at else {
+            let id = self.inner.display().id();
+            if self.restarts >= MAX_CAPTURE_RESTARTS {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "Capture stream of display {} stopped, recovery failed {} times",
+                        id, self.restarts
+                    ),
+                ));
+            }
+            self.restarts += 1;
+            self.restart_at = Some(Instant::now() + CAPTURE_RESTART_DELAY);
+            log::warn!(
+                "Capture stream of display {} stopped, reconnecting capture ({}/{})",
+                id,
+                self.restarts,
+                MAX_CAPTURE_RESTARTS
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                format!("Reconnecting capture of display {}", id),
+            ));
+        };
+        if Instant::now() < restart_at {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.restart_at = None;
+        self.restart()?;
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    fn restart(&mut self) -> io::Result<()> {
+        let old = self.inner.display();
+        let display = match self.uuid {
+            Some(uuid) => quartz::Display::from_uuid(&uuid),
+            None => Some(old).filter(|d| d.is_online()),
+        };
+        let Some(display) = display else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Display {} is offline", old.id()),
+            ));
+        };
+        if display.id() != old.id() {
+            log::info!("Display {} is display {} now", old.id(), display.id());
+        }
+        if display.width() != self.inner.width() || display.height() != self.inner.height() {
+            // Let the caller re-create the capturer and notify the client of the new size.
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Display {} size changed", display.id()),
+            ));
+        }
+        self.inner = Self::start(display, self.inner.format(), self.frame.clone())?;
+        Ok(())
     }
 
     pub fn width(&self) -> usize {
@@ -40,12 +155,15 @@ impl Capturer {
     }
 
     pub fn height(&self) -> usize {
-        self.inner.height()
+        self.inner.height() - self.crop_top
     }
 }
 
 impl crate::TraitCapturer for Capturer {
     fn frame<'a>(&'a mut self, _timeout_ms: std::time::Duration) -> io::Result<Frame<'a>> {
+        if self.inner.is_stopped() {
+            self.try_restart()?;
+        }
         match self.frame.try_lock() {
             Ok(mut handle) => {
                 let mut frame = None;
@@ -53,10 +171,19 @@ impl crate::TraitCapturer for Capturer {
 
                 match frame {
                     Some(mut frame) => {
+                        self.restarts = 0;
                         crate::would_block_if_equal(&mut self.saved_raw_data, frame.inner())?;
-                        frame.surface_to_bgra(self.height());
+                        let pixfmt = if self.inner.format() == PixelFormat::YCbCr420Video {
+                            frame.surface_to_nv12(self.crop_top, self.height());
+                            Pixfmt::NV12
+                        } else {
+                            frame.surface_to_bgra(self.crop_top, self.height());
+                            Pixfmt::BGRA
+                        };
+                        frame.crop_dirty_rects(self.crop_top);
                         Ok(Frame::PixelBuffer(PixelBuffer {
                             frame,
+                            pixfmt,
                             data: PhantomData,
                             width: self.width(),
                             height: self.height(),
@@ -76,6 +203,7 @@ impl crate::TraitCapturer for Capturer {
 
 pub struct PixelBuffer<'a> {
     frame: quartz::Frame,
+    pixfmt: Pixfmt,
     data: PhantomData<&'a [u8]>,
     width: usize,
     height: usize,
@@ -95,13 +223,16 @@ impl<'a> crate::TraitPixelBuffer for PixelBuffer<'a> {
     }
 
     fn stride(&self) -> Vec<usize> {
-        let mut v = Vec::new();
-        v.push(self.frame.stride());
-        v
+        self.frame.strides().to_vec()
     }
 
     fn pixfmt(&self) -> Pixfmt {
-        Pixfmt::BGRA
+        self.pixfmt
+    }
+
+    // CGDisplayStream reports the dirty rects of every update, ScreenCaptureKit is not used for now.
+    fn dirty_rects(&self) -> &[crate::DirtyRect] {
+        self.frame.dirty_rects()
     }
 }
 
@@ -125,7 +256,12 @@ impl Display {
     }
 
     pub fn height(&self) -> usize {
-        self.0.height()
+        self.0.height() - self.crop_top()
+    }
+
+    /// Rows in pixels cropped from the top, see `quartz::set_capture_crop_top`.
+    pub fn crop_top(&self) -> usize {
+        quartz::capture_crop_top(self.0.id()).min(self.0.height())
     }
 
     pub fn scale(&self) -> f64 {
@@ -142,7 +278,9 @@ impl Display {
 
     pub fn origin(&self) -> (i32, i32) {
         let o = self.0.bounds().origin;
-        (o.x as _, o.y as _)
+        // The origin is in points.
+        let crop = (self.crop_top() as f64 / self.scale()).round();
+        (o.x as _, (o.y + crop) as _)
     }
 
     pub fn is_primary(&self) -> bool {
@@ -31,18 +31,19 @@ impl Capturer {
     ) -> Result<Capturer, CGError> {
         let stopped = Arc::new(Mutex::new(false));
         let cloned_stopped = stopped.clone();
-        let handler: FrameAvailableHandler = ConcreteBlock::new(move |status, _, surface, _| {
-            use self::CGDisplayStreamFrameStatus::*;
-            if status == Stopped {
-                let mut lock = cloned_stopped.lock().unwrap();
-                *lock = true;
-                return;
-            }
-            if status == FrameComplete {
-                handler(unsafe { Frame::new(surface) });
-            }
-        })
-        .copy();
+        let handler: FrameAvailableHandler =
+            ConcreteBlock::new(move |status, _, surface, update| {
+                use self::CGDisplayStreamFrameStatus::*;
+                if status == Stopped {
+                    let mut lock = cloned_stopped.lock().unwrap();
+                    *lock = true;
+                    return;
+                }
+                if status == FrameComplete {
+                    handler(unsafe { Frame::new(surface, update) });
+                }
+            })
+            .copy();
 
         let queue = unsafe {
             dispatch_queue_create(
@@ -92,6 +93,10 @@ impl Capturer {
     pub fn display(&self) -> Display {
         self.display
     }
+    /// Whether the stream has been stopped, e.g. by display reconfiguration or permission revocation.
+    pub fn is_stopped(&self) -> bool {
+        *self.stopped.lock().unwrap()
+    }
 }
 
 impl Drop for Capturer {
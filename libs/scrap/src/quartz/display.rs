@@ -84,4 +84,24 @@ impl Display {
     pub fn bounds(self) -> CGRect {
         unsafe { CGDisplayBounds(self.0) }
     }
+
+    /// The UUID of the display, which unlike the id survives reconfigurations and hot-plugs.
+    pub fn uuid(self) -> Option<[u8; 16]> {
+        unsafe {
+            let uuid = CGDisplayCreateUUIDFromDisplayID(self.0);
+            if uuid.is_null() {
+                return None;
+            }
+            let bytes = CFUUIDGetUUIDBytes(uuid).bytes;
+            CFRelease(uuid);
+            Some(bytes)
+        }
+    }
+
+    pub fn from_uuid(uuid: &[u8; 16]) -> Option<Display> {
+        Self::online()
+            .ok()?
+            .into_iter()
+            .find(|d| d.uuid().as_ref() == Some(uuid))
+    }
 }
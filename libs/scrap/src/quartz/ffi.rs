@@ -13,6 +13,12 @@ pub type IOSurfaceRef = *mut c_void;
 pub type DispatchQueue = *mut c_void;
 pub type DispatchQueueAttr = *mut c_void;
 pub type CFAllocatorRef = *mut c_void;
+pub type CFUUIDRef = *mut c_void;
+
+#[repr(C)]
+pub struct CFUUIDBytes {
+    pub bytes: [u8; 16],
+}
 
 #[repr(C)]
 pub struct CFDictionaryKeyCallBacks {
@@ -60,6 +66,15 @@ pub enum CGDisplayStreamFrameStatus {
     __Nonexhaustive,
 }
 
+#[repr(i32)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CGDisplayStreamUpdateRectType {
+    RefreshedRects = 0,
+    MovedRects = 1,
+    DirtyRects = 2,
+    ReducedDirtyRects = 3,
+}
+
 #[repr(i32)]
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum CFNumberType {
@@ -177,6 +192,12 @@ extern "C" {
 
     pub fn CGDisplayStreamStop(displayStream: CGDisplayStreamRef) -> CGError;
 
+    pub fn CGDisplayStreamUpdateGetRects(
+        updateRef: CGDisplayStreamUpdateRef,
+        rectType: CGDisplayStreamUpdateRectType,
+        rectCount: *mut usize,
+    ) -> *const CGRect;
+
     pub fn CGMainDisplayID() -> u32;
     pub fn CGDisplayPixelsWide(display: u32) -> usize;
     pub fn CGDisplayPixelsHigh(display: u32) -> usize;
@@ -205,6 +226,8 @@ extern "C" {
     pub fn IOSurfaceUnlock(buffer: IOSurfaceRef, options: u32, seed: *mut u32) -> i32;
     pub fn IOSurfaceGetBaseAddressOfPlane(buffer: IOSurfaceRef, index: usize) -> *mut c_void;
     pub fn IOSurfaceGetBytesPerRowOfPlane(buffer: IOSurfaceRef, index: usize) -> usize;
+    pub fn IOSurfaceGetWidth(buffer: IOSurfaceRef) -> usize;
+    pub fn IOSurfaceGetHeight(buffer: IOSurfaceRef) -> usize;
 
     // Dispatch
 
@@ -238,4 +261,7 @@ extern "C" {
 
     pub fn CFRetain(cf: *const c_void);
     pub fn CFRelease(cf: *const c_void);
+
+    pub fn CGDisplayCreateUUIDFromDisplayID(display: u32) -> CFUUIDRef;
+    pub fn CFUUIDGetUUIDBytes(uuid: CFUUIDRef) -> CFUUIDBytes;
 }
@@ -1,16 +1,21 @@
 use std::{ops, ptr, slice};
 
 use super::ffi::*;
+use crate::DirtyRect;
+
+// More dirty rects are collapsed into a single one covering the frame.
+const MAX_DIRTY_RECTS: usize = 32;
 
 pub struct Frame {
     surface: IOSurfaceRef,
     inner: &'static [u8],
-    bgra: Vec<u8>,
-    bgra_stride: usize,
+    data: Vec<u8>,
+    strides: Vec<usize>,
+    dirty_rects: Vec<DirtyRect>,
 }
 
 impl Frame {
-    pub unsafe fn new(surface: IOSurfaceRef) -> Frame {
+    pub unsafe fn new(surface: IOSurfaceRef, update: CGDisplayStreamUpdateRef) -> Frame {
         CFRetain(surface);
         IOSurfaceIncrementUseCount(surface);
 
@@ -21,11 +26,66 @@ impl Frame {
             IOSurfaceGetAllocSize(surface),
         );
 
+        let mut dirty_rects = Vec::new();
+        if !update.is_null() {
+            let mut count = 0;
+            let rects = CGDisplayStreamUpdateGetRects(
+                update,
+                CGDisplayStreamUpdateRectType::DirtyRects,
+                &mut count,
+            );
+            if !rects.is_null() {
+                dirty_rects = slice::from_raw_parts(rects, count)
+                    .iter()
+                    .map(|r| DirtyRect {
+                        x: r.origin.x as _,
+                        y: r.origin.y as _,
+                        width: r.size.width as _,
+                        height: r.size.height as _,
+                    })
+                    .collect();
+            }
+        }
+
         Frame {
             surface,
             inner,
-            bgra: Vec::new(),
-            bgra_stride: 0,
+            data: Vec::new(),
+            strides: Vec::new(),
+            dirty_rects,
+        }
+    }
+
+    /// The regions changed since the previous frame, in display pixels.
+    #[inline]
+    pub fn dirty_rects(&self) -> &[DirtyRect] {
+        &self.dirty_rects
+    }
+
+    /// Keep the dirty rects of a frame which is replaced before being consumed.
+    pub fn merge_dirty_rects(&mut self, previous: &Frame) {
+        let full = self.full_rect();
+        if self.dirty_rects.contains(&full) {
+            return;
+        }
+        for r in previous.dirty_rects.iter() {
+            if !self.dirty_rects.contains(r) {
+                self.dirty_rects.push(*r);
+            }
+        }
+        if self.dirty_rects.len() > MAX_DIRTY_RECTS || self.dirty_rects.contains(&full) {
+            self.dirty_rects = vec![full];
+        }
+    }
+
+    fn full_rect(&self) -> DirtyRect {
+        unsafe {
+            DirtyRect {
+                x: 0,
+                y: 0,
+                width: IOSurfaceGetWidth(self.surface) as _,
+                height: IOSurfaceGetHeight(self.surface) as _,
+            }
         }
     }
 
@@ -34,28 +94,56 @@ impl Frame {
         self.inner
     }
 
-    pub fn stride(&self) -> usize {
-        self.bgra_stride
+    /// The stride of every plane copied by `surface_to_bgra` or `surface_to_nv12`.
+    pub fn strides(&self) -> &[usize] {
+        &self.strides
+    }
+
+    /// Copy `h` rows starting at row `top`.
+    pub fn surface_to_bgra<'a>(&'a mut self, top: usize, h: usize) {
+        self.copy_planes(&[(top, h)]);
     }
 
-    pub fn surface_to_bgra<'a>(&'a mut self, h: usize) {
+    /// Copy the Y and interleaved UV planes of a 420v surface, `top` must be even.
+    pub fn surface_to_nv12<'a>(&'a mut self, top: usize, h: usize) {
+        self.copy_planes(&[(top, h), (top / 2, (h + 1) / 2)]);
+    }
+
+    fn copy_planes(&mut self, planes: &[(usize, usize)]) {
         unsafe {
-            let plane0 = IOSurfaceGetBaseAddressOfPlane(self.surface, 0);
-            self.bgra_stride = IOSurfaceGetBytesPerRowOfPlane(self.surface, 0);
-            self.bgra.resize(self.bgra_stride * h, 0);
-            std::ptr::copy_nonoverlapping(
-                plane0 as _,
-                self.bgra.as_mut_ptr(),
-                self.bgra_stride * h,
-            );
+            self.strides.clear();
+            self.data.clear();
+            for (i, (top, h)) in planes.iter().enumerate() {
+                let plane = IOSurfaceGetBaseAddressOfPlane(self.surface, i);
+                let stride = IOSurfaceGetBytesPerRowOfPlane(self.surface, i);
+                self.strides.push(stride);
+                self.data.extend_from_slice(slice::from_raw_parts(
+                    (plane as *const u8).add(stride * top),
+                    stride * h,
+                ));
+            }
+        }
+    }
+
+    /// Move the dirty rects into the frame cropped by `top` rows.
+    pub fn crop_dirty_rects(&mut self, top: usize) {
+        if top == 0 {
+            return;
         }
+        let top = top as i32;
+        self.dirty_rects.retain_mut(|r| {
+            let bottom = r.y + r.height;
+            r.y = (r.y - top).max(0);
+            r.height = bottom - top - r.y;
+            r.height > 0
+        });
     }
 }
 
 impl ops::Deref for Frame {
     type Target = [u8];
     fn deref<'a>(&'a self) -> &'a [u8] {
-        &self.bgra
+        &self.data
     }
 }
 
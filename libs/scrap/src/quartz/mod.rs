@@ -10,8 +10,34 @@ mod display;
 pub mod ffi;
 mod frame;
 
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 lazy_static::lazy_static! {
     pub static ref ENABLE_RETINA: Arc<Mutex<bool>> = Arc::new(Mutex::new(true));
+    // Display id -> rows in pixels cropped from the top, e.g. the notch area.
+    pub static ref CAPTURE_CROP_TOP: Arc<Mutex<HashMap<u32, usize>>> = Default::default();
+}
+
+/// Crop `rows` pixels from the top of the display, applies to displays and capturers created afterwards.
+/// Rows are rounded down to even, so the chroma planes of NV12 frames stay aligned.
+pub fn set_capture_crop_top(display: u32, rows: usize) {
+    let rows = rows / 2 * 2;
+    let mut lock = CAPTURE_CROP_TOP.lock().unwrap();
+    if rows == 0 {
+        lock.remove(&display);
+    } else {
+        lock.insert(display, rows);
+    }
+}
+
+pub fn capture_crop_top(display: u32) -> usize {
+    CAPTURE_CROP_TOP
+        .lock()
+        .unwrap()
+        .get(&display)
+        .cloned()
+        .unwrap_or(0)
 }
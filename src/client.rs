@@ -526,6 +526,10 @@ impl Client {
                 }
                 direct = false;
             } else {
+                #[cfg(target_os = "macos")]
+                if is_local && crate::platform::macos::is_local_network_permitted() == Some(false) {
+                    bail!("LocalNetworkDenied: Local network access is denied, please enable it in System Settings > Privacy & Security > Local Network");
+                }
                 bail!("Failed to make direct connection to remote desktop");
             }
         }
@@ -1581,6 +1581,19 @@ impl<T: InvokeUiSession> Remote<T> {
                             );
                         }
                     }
+                    Some(misc::Union::CaptureReconnecting(reconnecting)) => {
+                        let (msgtype, title, text) = (
+                            "nook-nocancel-hasclose",
+                            "Prompt",
+                            "capture_reconnecting_tip",
+                        );
+                        if reconnecting {
+                            self.handler.msgbox(msgtype, title, text, "");
+                        } else {
+                            self.handler
+                                .cancel_msgbox(&format!("{}-{}-{}-", msgtype, title, text));
+                        }
+                    }
                     Some(misc::Union::SwitchBack(_)) => {
                         #[cfg(feature = "flutter")]
                         self.handler.switch_back(&self.handler.get_id());
@@ -185,8 +185,22 @@ fn send_query() -> ResultType<Vec<UdpSocket>> {
     msg_out.set_peer_discovery(peer);
     let out = msg_out.write_to_bytes()?;
     let maddr = SocketAddr::from(([255, 255, 255, 255], get_broadcast_port()));
+    let mut sent = false;
     for socket in &sockets {
-        allow_err!(socket.send_to(&out, maddr));
+        match socket.send_to(&out, maddr) {
+            Ok(_) => sent = true,
+            Err(err) => log::debug!("Failed to send discover ping: {}", err),
+        }
+    }
+    if !sent {
+        // Only explain a failure, the broadcast itself triggers the permission prompt.
+        #[cfg(target_os = "macos")]
+        if crate::platform::macos::is_local_network_permitted() == Some(false) {
+            bail!(
+                "LocalNetworkDenied: Local network access is denied, LAN discovery is unavailable"
+            );
+        }
+        log::warn!("Failed to send discover ping on any interface");
     }
     log::info!("discover ping sent");
     Ok(sockets)
@@ -644,5 +644,6 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Parent directory", "父目录"),
         ("Resume", "继续"),
         ("Invalid file name", "无效文件名"),
+        ("capture_reconnecting_tip", "屏幕捕获已中断，正在重新连接..."),
     ].iter().cloned().collect();
 }
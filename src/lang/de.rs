@@ -644,5 +644,6 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Parent directory", "Übergeordnetes Verzeichnis"),
         ("Resume", "Fortsetzen"),
         ("Invalid file name", "Ungültiger Dateiname"),
+        ("capture_reconnecting_tip", ""),
     ].iter().cloned().collect();
 }
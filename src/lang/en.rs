@@ -234,5 +234,6 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("About RustDesk", ""),
         ("network_error_tip", "Please check your network connection, then click retry."),
         ("enable-trusted-devices-tip", "Skip 2FA verification on trusted devices"),
+        ("capture_reconnecting_tip", "The screen capture stopped, reconnecting..."),
     ].iter().cloned().collect();
 }
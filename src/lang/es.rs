@@ -644,5 +644,6 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Parent directory", "Directorio superior"),
         ("Resume", "Continuar"),
         ("Invalid file name", "Nombre de archivo no válido"),
+        ("capture_reconnecting_tip", ""),
     ].iter().cloned().collect();
 }
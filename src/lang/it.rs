@@ -644,5 +644,6 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Parent directory", "Cartella principale"),
         ("Resume", "Riprendi"),
         ("Invalid file name", "Nome file non valido"),
+        ("capture_reconnecting_tip", ""),
     ].iter().cloned().collect();
 }
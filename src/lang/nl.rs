@@ -644,5 +644,6 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Parent directory", "Hoofdmap"),
         ("Resume", "Hervatten"),
         ("Invalid file name", "Ongeldige bestandsnaam"),
+        ("capture_reconnecting_tip", ""),
     ].iter().cloned().collect();
 }
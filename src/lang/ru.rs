@@ -644,5 +644,6 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Parent directory", ""),
         ("Resume", ""),
         ("Invalid file name", ""),
+        ("capture_reconnecting_tip", ""),
     ].iter().cloned().collect();
 }
@@ -1,8 +1,21 @@
 #import <AVFoundation/AVFoundation.h>
 #import <AppKit/AppKit.h>
+#import <Carbon/Carbon.h>
+#import <CoreMedia/CoreMedia.h>
+#import <Metal/Metal.h>
+#import <IOKit/IOKitLib.h>
+#import <IOKit/graphics/IOGraphicsLib.h>
 #import <IOKit/hidsystem/IOHIDLib.h>
+#import <IOKit/pwr_mgt/IOPMLib.h>
+#import <IOKit/IOMessage.h>
+#include <atomic>
+#include <mutex>
 #include <Security/Authorization.h>
 #include <Security/AuthorizationTags.h>
+#if __has_include(<ScreenCaptureKit/ScreenCaptureKit.h>)
+#import <ScreenCaptureKit/ScreenCaptureKit.h>
+#define HAS_SCREEN_CAPTURE_KIT 1
+#endif
 
 extern "C" bool CanUseNewApiForScreenCaptureCheck() {
     #ifdef NO_InputMonitoringAuthStatus
@@ -68,6 +81,25 @@ extern "C" bool InputMonitoringAuthStatus(bool prompt) {
     #endif
 }
 
+// 0: granted, 1: denied, 2: not determined yet. Never prompts.
+extern "C" int InputMonitoringAccessType() {
+    #ifdef NO_InputMonitoringAuthStatus
+    return 0;
+    #else
+    if (floor(NSAppKitVersionNumber) >= NSAppKitVersionNumber10_15) {
+        switch (IOHIDCheckAccess(kIOHIDRequestTypeListenEvent)) {
+            case kIOHIDAccessTypeGranted:
+                return 0;
+            case kIOHIDAccessTypeDenied:
+                return 1;
+            default:
+                return 2;
+        }
+    }
+    return 0;
+    #endif
+}
+
 extern "C" bool Elevate(char* process, char** args) {
     AuthorizationRef authRef;
     OSStatus status;
@@ -163,7 +195,8 @@ extern "C" bool MacGetModeNum(CGDirectDisplayID display, uint32_t *numModes) {
     return true;
 }
 
-extern "C" bool MacGetModes(CGDirectDisplayID display, uint32_t *widths, uint32_t *heights, uint32_t max, uint32_t *numModes) {
+// All modes with the bit depth of the current mode, including the ones of other refresh rates.
+extern "C" bool MacGetModes(CGDirectDisplayID display, uint32_t *widths, uint32_t *heights, double *rates, uint32_t max, uint32_t *numModes) {
     CGDisplayModeRef currentMode = CGDisplayCopyDisplayMode(display);
     if (currentMode == NULL) {
         return false;
@@ -177,10 +210,10 @@ extern "C" bool MacGetModes(CGDirectDisplayID display, uint32_t *widths, uint32_
     uint32_t realNum = 0;
     for (uint32_t i = 0; i < allModeCount && realNum < max; i++) {
         CGDisplayModeRef mode = (CGDisplayModeRef)CFArrayGetValueAtIndex(allModes, i);
-        if (CGDisplayModeGetRefreshRate(currentMode) == CGDisplayModeGetRefreshRate(mode) &&
-            bitDepth(currentMode) == bitDepth(mode)) {
+        if (bitDepth(currentMode) == bitDepth(mode)) {
             widths[realNum] = (uint32_t)CGDisplayModeGetWidth(mode);
             heights[realNum] = (uint32_t)CGDisplayModeGetHeight(mode);
+            rates[realNum] = CGDisplayModeGetRefreshRate(mode);
             realNum++;
         }
     }
@@ -220,7 +253,13 @@ static bool setDisplayToMode(CGDirectDisplayID display, CGDisplayModeRef mode) {
     return true;
 }
 
-extern "C" bool MacSetMode(CGDirectDisplayID display, uint32_t width, uint32_t height)
+static bool sameRefreshRate(double a, double b) {
+    // e.g. 59.94 and 60
+    return fabs(a - b) < 0.5;
+}
+
+// rate <= 0 means any rate, the current one is preferred.
+extern "C" bool MacSetMode(CGDirectDisplayID display, uint32_t width, uint32_t height, double rate)
 {
     bool ret = false;
     CGDisplayModeRef currentMode = CGDisplayCopyDisplayMode(display);
@@ -232,18 +271,858 @@ extern "C" bool MacSetMode(CGDirectDisplayID display, uint32_t width, uint32_t h
         CGDisplayModeRelease(currentMode);
         return ret;
     }
+    bool anyRate = rate <= 0;
+    if (anyRate) {
+        rate = CGDisplayModeGetRefreshRate(currentMode);
+    }
+    CGDisplayModeRef fallback = NULL;
     int numModes = CFArrayGetCount(allModes);
     for (int i = 0; i < numModes; i++) {
         CGDisplayModeRef mode = (CGDisplayModeRef)CFArrayGetValueAtIndex(allModes, i);
         if (width == CGDisplayModeGetWidth(mode) &&
             height == CGDisplayModeGetHeight(mode) && 
-            CGDisplayModeGetRefreshRate(currentMode) == CGDisplayModeGetRefreshRate(mode) &&
             bitDepth(currentMode) == bitDepth(mode)) {
-            ret = setDisplayToMode(display, mode);
-            break;
+            if (sameRefreshRate(rate, CGDisplayModeGetRefreshRate(mode))) {
+                fallback = NULL;
+                ret = setDisplayToMode(display, mode);
+                break;
+            }
+            if (anyRate && fallback == NULL) {
+                fallback = mode;
+            }
         }
     }
+    if (fallback != NULL) {
+        ret = setDisplayToMode(display, fallback);
+    }
     CGDisplayModeRelease(currentMode);
     CFRelease(allModes);
     return ret;
-}
\ No newline at end of file
+}
+
+// Read a string property of IOPlatformExpertDevice, e.g. IOPlatformSerialNumber or IOPlatformUUID.
+extern "C" bool MacGetPlatformProperty(const char *key, char *buf, uint32_t len) {
+    if (key == NULL || buf == NULL || len == 0) {
+        return false;
+    }
+    io_service_t service = IOServiceGetMatchingService(MACH_PORT_NULL, IOServiceMatching("IOPlatformExpertDevice"));
+    if (service == IO_OBJECT_NULL) {
+        return false;
+    }
+    bool ret = false;
+    CFStringRef cfKey = CFStringCreateWithCString(kCFAllocatorDefault, key, kCFStringEncodingUTF8);
+    if (cfKey != NULL) {
+        CFTypeRef value = IORegistryEntryCreateCFProperty(service, cfKey, kCFAllocatorDefault, 0);
+        if (value != NULL) {
+            if (CFGetTypeID(value) == CFStringGetTypeID()) {
+                ret = CFStringGetCString((CFStringRef)value, buf, len, kCFStringEncodingUTF8);
+            }
+            CFRelease(value);
+        }
+        CFRelease(cfKey);
+    }
+    IOObjectRelease(service);
+    return ret;
+}
+
+// Wake the display if it is asleep and reset the idle timer, like a local user activity.
+extern "C" bool MacWakeDisplay() {
+    // Reuse the same assertion id, the system extends it on every declaration.
+    static IOPMAssertionID assertionID = kIOPMNullAssertionID;
+    IOReturn ret = IOPMAssertionDeclareUserActivity(CFSTR("RustDesk wake display"), kIOPMUserActiveLocal, &assertionID);
+    return ret == kIOReturnSuccess;
+}
+
+// System audio capture with ScreenCaptureKit, macOS >= 13.
+// Video is captured by CGDisplayStream, so the stream here only carries audio,
+// the video output is not added and its size is kept minimal.
+
+typedef void (*CaptureAudioCallback)(const float *data, uint32_t frames, uint32_t channels, uint32_t sampleRate);
+
+#define CAPTURE_AUDIO_OK 0
+#define CAPTURE_AUDIO_UNSUPPORTED 1
+#define CAPTURE_AUDIO_FAILED 2
+
+#ifdef HAS_SCREEN_CAPTURE_KIT
+API_AVAILABLE(macos(13.0))
+@interface CaptureAudioOutput : NSObject <SCStreamOutput, SCStreamDelegate>
+@property CaptureAudioCallback callback;
+@end
+
+@implementation CaptureAudioOutput
+- (void)stream:(SCStream *)stream didOutputSampleBuffer:(CMSampleBufferRef)sampleBuffer ofType:(SCStreamOutputType)type {
+    if (type != SCStreamOutputTypeAudio || !CMSampleBufferIsValid(sampleBuffer) || self.callback == NULL) {
+        return;
+    }
+    CMFormatDescriptionRef format = CMSampleBufferGetFormatDescription(sampleBuffer);
+    const AudioStreamBasicDescription *asbd = CMAudioFormatDescriptionGetStreamBasicDescription(format);
+    if (asbd == NULL || !(asbd->mFormatFlags & kAudioFormatFlagIsFloat) || asbd->mBitsPerChannel != 32) {
+        return;
+    }
+    size_t size = 0;
+    CMSampleBufferGetAudioBufferListWithRetainedBlockBuffer(sampleBuffer, &size, NULL, 0, NULL, NULL, 0, NULL);
+    if (size == 0) {
+        return;
+    }
+    AudioBufferList *list = (AudioBufferList *)malloc(size);
+    CMBlockBufferRef block = NULL;
+    OSStatus status = CMSampleBufferGetAudioBufferListWithRetainedBlockBuffer(sampleBuffer, NULL, list, size, NULL, NULL,
+        kCMSampleBufferFlag_AudioBufferList_Assure16ByteAlignment, &block);
+    if (status == noErr) {
+        uint32_t frames = (uint32_t)CMSampleBufferGetNumSamples(sampleBuffer);
+        uint32_t channels = asbd->mChannelsPerFrame;
+        uint32_t sampleRate = (uint32_t)asbd->mSampleRate;
+        if (asbd->mFormatFlags & kAudioFormatFlagIsNonInterleaved) {
+            // One buffer per channel, interleave them.
+            if (list->mNumberBuffers == channels) {
+                float *data = (float *)malloc(sizeof(float) * frames * channels);
+                for (uint32_t c = 0; c < channels; c++) {
+                    const float *src = (const float *)list->mBuffers[c].mData;
+                    for (uint32_t f = 0; f < frames; f++) {
+                        data[f * channels + c] = src[f];
+                    }
+                }
+                self.callback(data, frames, channels, sampleRate);
+                free(data);
+            }
+        } else if (list->mNumberBuffers >= 1) {
+            self.callback((const float *)list->mBuffers[0].mData, frames, channels, sampleRate);
+        }
+        CFRelease(block);
+    }
+    free(list);
+}
+
+- (void)stream:(SCStream *)stream didStopWithError:(NSError *)error {
+    NSLog(@"Audio capture stream stopped: %@", error);
+}
+@end
+
+static SCStream *gCaptureAudioStream API_AVAILABLE(macos(13.0)) = nil;
+static CaptureAudioOutput *gCaptureAudioOutput API_AVAILABLE(macos(13.0)) = nil;
+static dispatch_queue_t gCaptureAudioQueue = NULL;
+
+API_AVAILABLE(macos(13.0))
+static void stopCaptureAudio() {
+    if (gCaptureAudioStream != nil) {
+        dispatch_semaphore_t sem = dispatch_semaphore_create(0);
+        [gCaptureAudioStream stopCaptureWithCompletionHandler:^(NSError *error) {
+            dispatch_semaphore_signal(sem);
+        }];
+        dispatch_semaphore_wait(sem, dispatch_time(DISPATCH_TIME_NOW, 3 * NSEC_PER_SEC));
+        dispatch_release(sem);
+        [gCaptureAudioStream release];
+        gCaptureAudioStream = nil;
+    }
+    if (gCaptureAudioOutput != nil) {
+        [gCaptureAudioOutput release];
+        gCaptureAudioOutput = nil;
+    }
+}
+
+// Must not be called on the main thread, it waits for the completion handlers.
+API_AVAILABLE(macos(13.0))
+static int startCaptureAudio(CaptureAudioCallback callback) {
+    __block SCShareableContent *content = nil;
+    dispatch_semaphore_t sem = dispatch_semaphore_create(0);
+    [SCShareableContent getShareableContentWithCompletionHandler:^(SCShareableContent *c, NSError *error) {
+        if (error != nil) {
+            NSLog(@"Failed to get shareable content: %@", error);
+        }
+        content = [c retain];
+        dispatch_semaphore_signal(sem);
+    }];
+    dispatch_semaphore_wait(sem, dispatch_time(DISPATCH_TIME_NOW, 5 * NSEC_PER_SEC));
+    if (content == nil || content.displays.count == 0) {
+        [content release];
+        dispatch_release(sem);
+        return CAPTURE_AUDIO_FAILED;
+    }
+    SCContentFilter *filter = [[SCContentFilter alloc] initWithDisplay:content.displays.firstObject excludingWindows:@[]];
+    [content release];
+    SCStreamConfiguration *config = [[SCStreamConfiguration alloc] init];
+    config.capturesAudio = YES;
+    config.excludesCurrentProcessAudio = YES;
+    config.sampleRate = 48000;
+    config.channelCount = 2;
+    config.width = 2;
+    config.height = 2;
+    config.minimumFrameInterval = CMTimeMake(1, 1);
+    gCaptureAudioOutput = [[CaptureAudioOutput alloc] init];
+    gCaptureAudioOutput.callback = callback;
+    gCaptureAudioStream = [[SCStream alloc] initWithFilter:filter configuration:config delegate:gCaptureAudioOutput];
+    [filter release];
+    [config release];
+    if (gCaptureAudioQueue == NULL) {
+        gCaptureAudioQueue = dispatch_queue_create("rustdesk.capture.audio", DISPATCH_QUEUE_SERIAL);
+    }
+    NSError *error = nil;
+    if (![gCaptureAudioStream addStreamOutput:gCaptureAudioOutput type:SCStreamOutputTypeAudio sampleHandlerQueue:gCaptureAudioQueue error:&error]) {
+        NSLog(@"Failed to add audio stream output: %@", error);
+        stopCaptureAudio();
+        dispatch_release(sem);
+        return CAPTURE_AUDIO_FAILED;
+    }
+    __block bool started = false;
+    [gCaptureAudioStream startCaptureWithCompletionHandler:^(NSError *error) {
+        if (error != nil) {
+            NSLog(@"Failed to start audio capture: %@", error);
+        } else {
+            started = true;
+        }
+        dispatch_semaphore_signal(sem);
+    }];
+    dispatch_semaphore_wait(sem, dispatch_time(DISPATCH_TIME_NOW, 5 * NSEC_PER_SEC));
+    dispatch_release(sem);
+    if (!started) {
+        stopCaptureAudio();
+        return CAPTURE_AUDIO_FAILED;
+    }
+    return CAPTURE_AUDIO_OK;
+}
+#endif
+
+extern "C" int MacSetCaptureAudio(bool enabled, CaptureAudioCallback callback) {
+#ifdef HAS_SCREEN_CAPTURE_KIT
+    if (@available(macOS 13.0, *)) {
+        @synchronized ([CaptureAudioOutput class]) {
+            stopCaptureAudio();
+            if (!enabled) {
+                return CAPTURE_AUDIO_OK;
+            }
+            return startCaptureAudio(callback);
+        }
+    }
+#endif
+    return enabled ? CAPTURE_AUDIO_UNSUPPORTED : CAPTURE_AUDIO_OK;
+}
+
+// Dual-GPU (muxed) MacBook Pros have a built-in low power GPU and a built-in discrete GPU.
+extern "C" bool MacHasGpuMux() {
+    bool lowPower = false;
+    bool highPower = false;
+    NSArray<id<MTLDevice>> *devices = MTLCopyAllDevices();
+    for (id<MTLDevice> device in devices) {
+        if (device.isRemovable) {
+            continue;
+        }
+        if (device.isLowPower) {
+            lowPower = true;
+        } else {
+            highPower = true;
+        }
+    }
+    [devices release];
+    return lowPower && highPower;
+}
+
+// The GPU driving the main display.
+extern "C" bool MacGetCurrentGpu(char *name, uint32_t len, bool *lowPower) {
+    id<MTLDevice> device = CGDirectDisplayCopyCurrentMetalDevice(CGMainDisplayID());
+    if (device == nil) {
+        return false;
+    }
+    bool ret = [device.name getCString:name maxLength:len encoding:NSUTF8StringEncoding];
+    *lowPower = device.isLowPower;
+    [device release];
+    return ret;
+}
+
+// Holding the default Metal device keeps the discrete GPU active on muxed Macs.
+static id<MTLDevice> gDiscreteGpu = nil;
+
+extern "C" bool MacRequestDiscreteGpu(bool enable) {
+    if (!enable) {
+        if (gDiscreteGpu != nil) {
+            [gDiscreteGpu release];
+            gDiscreteGpu = nil;
+        }
+        return true;
+    }
+    if (gDiscreteGpu == nil) {
+        gDiscreteGpu = MTLCreateSystemDefaultDevice();
+    }
+    return gDiscreteGpu != nil && !gDiscreteGpu.isLowPower;
+}
+
+static uint32_t numberValue(CFDictionaryRef dict, CFStringRef key) {
+    uint32_t value = 0;
+    CFNumberRef n = dict ? (CFNumberRef)CFDictionaryGetValue(dict, key) : NULL;
+    if (n != NULL && CFGetTypeID(n) == CFNumberGetTypeID()) {
+        CFNumberGetValue(n, kCFNumberSInt32Type, &value);
+    }
+    return value;
+}
+
+static uint32_t copyEdid(CFDataRef edid, uint8_t *buf, uint32_t len) {
+    uint32_t size = (uint32_t)CFDataGetLength(edid);
+    if (size > len) {
+        return size;
+    }
+    CFDataGetBytes(edid, CFRangeMake(0, size), buf);
+    return size;
+}
+
+// Copy the raw EDID of the display to buf.
+// Returns the EDID size, which is bigger than len if buf is too small, or 0 if not found.
+extern "C" uint32_t MacGetDisplayEdid(CGDirectDisplayID display, uint8_t *buf, uint32_t len) {
+    uint32_t vendor = CGDisplayVendorNumber(display);
+    uint32_t model = CGDisplayModelNumber(display);
+    uint32_t ret = 0;
+    io_iterator_t iter;
+    // Intel Macs
+    if (IOServiceGetMatchingServices(MACH_PORT_NULL, IOServiceMatching("IODisplayConnect"), &iter) == KERN_SUCCESS) {
+        io_service_t service;
+        while (ret == 0 && (service = IOIteratorNext(iter)) != IO_OBJECT_NULL) {
+            CFDictionaryRef info = IODisplayCreateInfoDictionary(service, kIODisplayOnlyPreferredName);
+            if (info != NULL) {
+                CFDataRef edid = (CFDataRef)CFDictionaryGetValue(info, CFSTR(kIODisplayEDIDKey));
+                if (edid != NULL && CFGetTypeID(edid) == CFDataGetTypeID() &&
+                    numberValue(info, CFSTR(kDisplayVendorID)) == vendor &&
+                    numberValue(info, CFSTR(kDisplayProductID)) == model) {
+                    ret = copyEdid(edid, buf, len);
+                }
+                CFRelease(info);
+            }
+            IOObjectRelease(service);
+        }
+        IOObjectRelease(iter);
+    }
+    if (ret != 0) {
+        return ret;
+    }
+    // Apple Silicon Macs
+    if (IOServiceGetMatchingServices(MACH_PORT_NULL, IOServiceMatching("AppleCLCD2"), &iter) == KERN_SUCCESS) {
+        io_service_t service;
+        while (ret == 0 && (service = IOIteratorNext(iter)) != IO_OBJECT_NULL) {
+            CFDictionaryRef attrs = (CFDictionaryRef)IORegistryEntryCreateCFProperty(service, CFSTR("DisplayAttributes"), kCFAllocatorDefault, 0);
+            if (attrs != NULL) {
+                CFDictionaryRef product = (CFDictionaryRef)CFDictionaryGetValue(attrs, CFSTR("ProductAttributes"));
+                if (product != NULL && CFGetTypeID(product) == CFDictionaryGetTypeID() &&
+                    numberValue(product, CFSTR("LegacyManufacturerID")) == vendor &&
+                    numberValue(product, CFSTR("ProductID")) == model) {
+                    CFDataRef edid = (CFDataRef)IORegistryEntryCreateCFProperty(service, CFSTR("EDID"), kCFAllocatorDefault, 0);
+                    if (edid != NULL) {
+                        if (CFGetTypeID(edid) == CFDataGetTypeID()) {
+                            ret = copyEdid(edid, buf, len);
+                        }
+                        CFRelease(edid);
+                    }
+                }
+                CFRelease(attrs);
+            }
+            IOObjectRelease(service);
+        }
+        IOObjectRelease(iter);
+    }
+    return ret;
+}
+
+// selector: kIOHIDCapsLockState or kIOHIDNumLockState
+extern "C" bool MacGetModifierLockState(int selector, bool *state) {
+    io_service_t service = IOServiceGetMatchingService(MACH_PORT_NULL, IOServiceMatching(kIOHIDSystemClass));
+    if (service == IO_OBJECT_NULL) {
+        return false;
+    }
+    io_connect_t connect = IO_OBJECT_NULL;
+    kern_return_t kr = IOServiceOpen(service, mach_task_self(), kIOHIDParamConnectType, &connect);
+    IOObjectRelease(service);
+    if (kr != KERN_SUCCESS) {
+        return false;
+    }
+    kr = IOHIDGetModifierLockState(connect, selector, state);
+    IOServiceClose(connect);
+    return kr == KERN_SUCCESS;
+}
+
+static NSAppearance *currentSystemAppearance() {
+    if (NSApp != nil) {
+        return [NSApp effectiveAppearance];
+    }
+    // No NSApplication in the service, fall back to the user's preference.
+    NSString *style = [[NSUserDefaults standardUserDefaults] stringForKey:@"AppleInterfaceStyle"];
+    if (@available(macOS 10.14, *)) {
+        if ([style isEqualToString:@"Dark"]) {
+            return [NSAppearance appearanceNamed:NSAppearanceNameDarkAqua];
+        }
+    }
+    return [NSAppearance appearanceNamed:NSAppearanceNameAqua];
+}
+
+// which: 0 accent color, 1 highlight color
+extern "C" bool MacGetSystemColor(int which, uint8_t *rgb) {
+    @autoreleasepool {
+        NSColor *color = nil;
+        if (which == 0) {
+            if (@available(macOS 10.14, *)) {
+                color = [NSColor controlAccentColor];
+            }
+        } else {
+            color = [NSColor selectedTextBackgroundColor];
+        }
+        if (color == nil) {
+            return false;
+        }
+        // Dynamic colors must be resolved against an appearance.
+        __block NSColor *resolved = nil;
+        NSAppearance *appearance = currentSystemAppearance();
+        if (@available(macOS 11.0, *)) {
+            [appearance performAsCurrentDrawingAppearance:^{
+                resolved = [color colorUsingColorSpace:[NSColorSpace sRGBColorSpace]];
+            }];
+        } else {
+            NSAppearance *saved = [NSAppearance currentAppearance];
+            [NSAppearance setCurrentAppearance:appearance];
+            resolved = [color colorUsingColorSpace:[NSColorSpace sRGBColorSpace]];
+            [NSAppearance setCurrentAppearance:saved];
+        }
+        if (resolved == nil) {
+            return false;
+        }
+        rgb[0] = (uint8_t)lround([resolved redComponent] * 255);
+        rgb[1] = (uint8_t)lround([resolved greenComponent] * 255);
+        rgb[2] = (uint8_t)lround([resolved blueComponent] * 255);
+        return true;
+    }
+}
+
+// event: 0 will sleep, 1 did wake, 2 will power off
+typedef void (*SleepWakeCallback)(int event);
+
+static io_connect_t sleepWakeRootPort = IO_OBJECT_NULL;
+static SleepWakeCallback sleepWakeCallback = NULL;
+
+static void sleepWakeHandler(void *refCon, io_service_t service, natural_t messageType, void *messageArgument) {
+    switch (messageType) {
+        case kIOMessageCanSystemSleep:
+            IOAllowPowerChange(sleepWakeRootPort, (long)messageArgument);
+            break;
+        case kIOMessageSystemWillSleep:
+            // Sleep is delayed until the callback returns, at most 30 seconds.
+            sleepWakeCallback(0);
+            IOAllowPowerChange(sleepWakeRootPort, (long)messageArgument);
+            break;
+        case kIOMessageSystemHasPoweredOn:
+            sleepWakeCallback(1);
+            break;
+        case kIOMessageSystemWillPowerOff:
+            sleepWakeCallback(2);
+            IOAllowPowerChange(sleepWakeRootPort, (long)messageArgument);
+            break;
+        default:
+            break;
+    }
+}
+
+// Blocks the calling thread to run the notifications, returns false if the registration failed.
+extern "C" bool MacRunSleepWakeObserver(SleepWakeCallback callback) {
+    IONotificationPortRef notifyPort = NULL;
+    io_object_t notifier = IO_OBJECT_NULL;
+    sleepWakeCallback = callback;
+    sleepWakeRootPort = IORegisterForSystemPower(NULL, &notifyPort, sleepWakeHandler, &notifier);
+    if (sleepWakeRootPort == IO_OBJECT_NULL) {
+        return false;
+    }
+    CFRunLoopAddSource(CFRunLoopGetCurrent(), IONotificationPortGetRunLoopSource(notifyPort), kCFRunLoopDefaultMode);
+    CFRunLoopRun();
+    IODeregisterForSystemPower(&notifier);
+    IOServiceClose(sleepWakeRootPort);
+    IONotificationPortDestroy(notifyPort);
+    return true;
+}
+
+typedef void (*SessionCallback)(int event);
+
+// Blocks the calling thread to run the notifications of fast user switching.
+// 0: the session became active, 1: resigned active.
+extern "C" void MacRunSessionObserver(SessionCallback callback) {
+    @autoreleasepool {
+        NSNotificationCenter *center = [[NSWorkspace sharedWorkspace] notificationCenter];
+        [center addObserverForName:NSWorkspaceSessionDidBecomeActiveNotification
+                            object:nil
+                             queue:nil
+                        usingBlock:^(NSNotification *note) {
+            callback(0);
+        }];
+        [center addObserverForName:NSWorkspaceSessionDidResignActiveNotification
+                            object:nil
+                             queue:nil
+                        usingBlock:^(NSNotification *note) {
+            callback(1);
+        }];
+        // Observers are not run loop sources, keep the run loop alive with a port.
+        [[NSRunLoop currentRunLoop] addPort:[NSMachPort port] forMode:NSDefaultRunLoopMode];
+    }
+    CFRunLoopRun();
+}
+
+typedef struct {
+    uint32_t display;
+    uint32_t width;
+    uint32_t height;
+    double refreshRate;
+    bool current;
+} MacDisplayMode;
+
+// Modes of all active displays with the bit depth of the current mode, in one pass.
+// Returns the total count, call again with a larger buffer if it is greater than max.
+extern "C" uint32_t MacGetAllDisplaysModes(MacDisplayMode *modes, uint32_t max) {
+    CGDirectDisplayID displays[16];
+    uint32_t displayCount = 0;
+    if (CGGetActiveDisplayList(16, displays, &displayCount) != kCGErrorSuccess) {
+        return 0;
+    }
+    uint32_t total = 0;
+    for (uint32_t d = 0; d < displayCount; d++) {
+        CGDisplayModeRef currentMode = CGDisplayCopyDisplayMode(displays[d]);
+        if (currentMode == NULL) {
+            continue;
+        }
+        CFArrayRef allModes = CGDisplayCopyAllDisplayModes(displays[d], NULL);
+        if (allModes == NULL) {
+            CGDisplayModeRelease(currentMode);
+            continue;
+        }
+        size_t currentDepth = bitDepth(currentMode);
+        uint32_t currentModeId = CGDisplayModeGetIODisplayModeID(currentMode);
+        CFIndex allModeCount = CFArrayGetCount(allModes);
+        for (CFIndex i = 0; i < allModeCount; i++) {
+            CGDisplayModeRef mode = (CGDisplayModeRef)CFArrayGetValueAtIndex(allModes, i);
+            if (bitDepth(mode) != currentDepth) {
+                continue;
+            }
+            if (total < max) {
+                modes[total].display = displays[d];
+                modes[total].width = (uint32_t)CGDisplayModeGetWidth(mode);
+                modes[total].height = (uint32_t)CGDisplayModeGetHeight(mode);
+                modes[total].refreshRate = CGDisplayModeGetRefreshRate(mode);
+                modes[total].current = CGDisplayModeGetIODisplayModeID(mode) == currentModeId;
+            }
+            total++;
+        }
+        CGDisplayModeRelease(currentMode);
+        CFRelease(allModes);
+    }
+    return total;
+}
+
+static CGEventMask inputEventMask() {
+    return CGEventMaskBit(kCGEventKeyDown) | CGEventMaskBit(kCGEventKeyUp) |
+        CGEventMaskBit(kCGEventFlagsChanged) | CGEventMaskBit(NSEventTypeSystemDefined) |
+        CGEventMaskBit(kCGEventLeftMouseDown) | CGEventMaskBit(kCGEventLeftMouseUp) |
+        CGEventMaskBit(kCGEventRightMouseDown) | CGEventMaskBit(kCGEventRightMouseUp) |
+        CGEventMaskBit(kCGEventOtherMouseDown) | CGEventMaskBit(kCGEventOtherMouseUp) |
+        CGEventMaskBit(kCGEventMouseMoved) | CGEventMaskBit(kCGEventLeftMouseDragged) |
+        CGEventMaskBit(kCGEventRightMouseDragged) | CGEventMaskBit(kCGEventOtherMouseDragged) |
+        CGEventMaskBit(kCGEventScrollWheel);
+}
+
+static std::mutex blockInputMutex;
+static CFMachPortRef blockInputTap = NULL;
+static CFRunLoopRef blockInputRunLoop = NULL;
+static bool blockInputStopping = false;
+static int64_t blockInputPassUserData = 0;
+
+static CGEventRef blockInputCallback(CGEventTapProxy proxy, CGEventType type, CGEventRef event, void *refcon) {
+    if (type == kCGEventTapDisabledByTimeout || type == kCGEventTapDisabledByUserInput) {
+        // The system disables slow taps, enable it again.
+        if (blockInputTap != NULL) {
+            CGEventTapEnable(blockInputTap, true);
+        }
+        return event;
+    }
+    // Let the injected events of the remote side through.
+    if (CGEventGetIntegerValueField(event, kCGEventSourceUserData) == blockInputPassUserData) {
+        return event;
+    }
+    return NULL;
+}
+
+// Create the tap swallowing local keyboard and mouse events.
+// Returns 0 if the permission is missing, 1 if created, 2 if it exists already.
+extern "C" int MacStartBlockInput(int64_t passUserData) {
+    std::lock_guard<std::mutex> lock(blockInputMutex);
+    if (blockInputTap != NULL) {
+        if (blockInputStopping) {
+            // Stopped but not torn down yet, keep it running.
+            blockInputStopping = false;
+            CGEventTapEnable(blockInputTap, true);
+        }
+        return 2;
+    }
+    blockInputPassUserData = passUserData;
+    blockInputStopping = false;
+    blockInputTap = CGEventTapCreate(kCGHIDEventTap, kCGHeadInsertEventTap, kCGEventTapOptionDefault, inputEventMask(), blockInputCallback, NULL);
+    return blockInputTap != NULL ? 1 : 0;
+}
+
+// Run the tap on the calling thread until MacStopBlockInput.
+extern "C" void MacRunBlockInput() {
+    CFRunLoopSourceRef source = NULL;
+    {
+        std::lock_guard<std::mutex> lock(blockInputMutex);
+        if (blockInputTap == NULL || blockInputRunLoop != NULL) {
+            return;
+        }
+        blockInputRunLoop = CFRunLoopGetCurrent();
+        source = CFMachPortCreateRunLoopSource(kCFAllocatorDefault, blockInputTap, 0);
+        CFRunLoopAddSource(blockInputRunLoop, source, kCFRunLoopCommonModes);
+        CGEventTapEnable(blockInputTap, true);
+    }
+    // CFRunLoopStop is lost if it comes before the loop runs, so the flag is checked as well.
+    while (true) {
+        CFRunLoopRunInMode(kCFRunLoopDefaultMode, 0.5, false);
+        std::lock_guard<std::mutex> lock(blockInputMutex);
+        if (blockInputStopping) {
+            break;
+        }
+    }
+    std::lock_guard<std::mutex> lock(blockInputMutex);
+    CFRunLoopRemoveSource(blockInputRunLoop, source, kCFRunLoopCommonModes);
+    CFRelease(source);
+    CFMachPortInvalidate(blockInputTap);
+    CFRelease(blockInputTap);
+    blockInputTap = NULL;
+    blockInputRunLoop = NULL;
+    blockInputStopping = false;
+}
+
+extern "C" void MacStopBlockInput() {
+    std::lock_guard<std::mutex> lock(blockInputMutex);
+    if (blockInputTap == NULL) {
+        return;
+    }
+    CGEventTapEnable(blockInputTap, false);
+    if (blockInputRunLoop != NULL) {
+        blockInputStopping = true;
+        CFRunLoopStop(blockInputRunLoop);
+    } else {
+        // Not running yet.
+        CFMachPortInvalidate(blockInputTap);
+        CFRelease(blockInputTap);
+        blockInputTap = NULL;
+    }
+}
+
+// CFAbsoluteTime of the last local input event, 0 if no monitor has started yet.
+static std::atomic<double> localInputTime(0);
+
+struct LocalInputContext {
+    CFMachPortRef tap;
+    int64_t passUserData;
+};
+
+static CGEventRef localInputCallback(CGEventTapProxy proxy, CGEventType type, CGEventRef event, void *refcon) {
+    LocalInputContext *ctx = (LocalInputContext *)refcon;
+    if (type == kCGEventTapDisabledByTimeout || type == kCGEventTapDisabledByUserInput) {
+        CGEventTapEnable(ctx->tap, true);
+        return event;
+    }
+    // The injected events of the remote side are not local activity.
+    if (CGEventGetIntegerValueField(event, kCGEventSourceUserData) != ctx->passUserData) {
+        localInputTime.store(CFAbsoluteTimeGetCurrent());
+    }
+    return event;
+}
+
+// Record the time of the local input with a listen-only tap until *exit.
+// Returns false at once if the tap cannot be created.
+extern "C" bool MacRunLocalInputMonitor(int64_t passUserData, const bool *exit) {
+    LocalInputContext ctx = {NULL, passUserData};
+    ctx.tap = CGEventTapCreate(kCGHIDEventTap, kCGTailAppendEventTap, kCGEventTapOptionListenOnly, inputEventMask(), localInputCallback, &ctx);
+    if (ctx.tap == NULL) {
+        return false;
+    }
+    localInputTime.store(CFAbsoluteTimeGetCurrent());
+    CFRunLoopSourceRef source = CFMachPortCreateRunLoopSource(kCFAllocatorDefault, ctx.tap, 0);
+    CFRunLoopAddSource(CFRunLoopGetCurrent(), source, kCFRunLoopDefaultMode);
+    CGEventTapEnable(ctx.tap, true);
+    while (!*exit) {
+        CFRunLoopRunInMode(kCFRunLoopDefaultMode, 0.5, false);
+    }
+    CFRunLoopRemoveSource(CFRunLoopGetCurrent(), source, kCFRunLoopDefaultMode);
+    CFRelease(source);
+    CFMachPortInvalidate(ctx.tap);
+    CFRelease(ctx.tap);
+    return true;
+}
+
+// Seconds since the last local input event, negative if no monitor has started.
+extern "C" double MacLocalInputIdleSeconds() {
+    double last = localInputTime.load();
+    if (last == 0) {
+        return -1;
+    }
+    return CFAbsoluteTimeGetCurrent() - last;
+}
+
+extern "C" bool MacIsDarkMode() {
+    @autoreleasepool {
+        NSAppearance *appearance = currentSystemAppearance();
+        if (@available(macOS 10.14, *)) {
+            NSAppearanceName name = [appearance bestMatchFromAppearancesWithNames:@[NSAppearanceNameAqua, NSAppearanceNameDarkAqua]];
+            return [name isEqualToString:NSAppearanceNameDarkAqua];
+        }
+        return false;
+    }
+}
+
+typedef void (*NotificationCallback)(void *context);
+
+// Blocks the calling thread to deliver the distributed notification `name` until *exit is true.
+extern "C" void MacRunDistributedNotificationWatcher(const char *name, NotificationCallback callback, void *context, const bool *exit) {
+    @autoreleasepool {
+        NSDistributedNotificationCenter *center = [NSDistributedNotificationCenter defaultCenter];
+        id observer = [center addObserverForName:[NSString stringWithUTF8String:name]
+                                          object:nil
+                                           queue:nil
+                                      usingBlock:^(NSNotification *note) {
+            callback(context);
+        }];
+        while (!__atomic_load_n(exit, __ATOMIC_SEQ_CST)) {
+            CFRunLoopRunInMode(kCFRunLoopDefaultMode, 0.5, false);
+        }
+        [center removeObserver:observer];
+    }
+}
+
+static bool copyInputSourceID(TISInputSourceRef source, char *buf, uint32_t len) {
+    CFStringRef sourceID = (CFStringRef)TISGetInputSourceProperty(source, kTISPropertyInputSourceID);
+    return sourceID != NULL && CFStringGetCString(sourceID, buf, len, kCFStringEncodingUTF8);
+}
+
+extern "C" bool MacGetCurrentInputSource(char *buf, uint32_t len) {
+    TISInputSourceRef source = TISCopyCurrentKeyboardInputSource();
+    if (source == NULL) {
+        return false;
+    }
+    bool res = copyInputSourceID(source, buf, len);
+    CFRelease(source);
+    return res;
+}
+
+// The ids of the enabled keyboard input sources the user can select, separated by '\n'.
+// Returns the length required, call again with a larger buffer if it is greater than len.
+extern "C" uint32_t MacListInputSources(char *buf, uint32_t len) {
+    @autoreleasepool {
+        NSDictionary *filter = @{
+            (NSString *)kTISPropertyInputSourceCategory: (NSString *)kTISCategoryKeyboardInputSource,
+            (NSString *)kTISPropertyInputSourceIsSelectCapable: @YES,
+        };
+        CFArrayRef sources = TISCreateInputSourceList((CFDictionaryRef)filter, false);
+        if (sources == NULL) {
+            return 0;
+        }
+        NSMutableArray *ids = [NSMutableArray array];
+        for (CFIndex i = 0; i < CFArrayGetCount(sources); i++) {
+            TISInputSourceRef source = (TISInputSourceRef)CFArrayGetValueAtIndex(sources, i);
+            NSString *sourceID = (NSString *)TISGetInputSourceProperty(source, kTISPropertyInputSourceID);
+            if (sourceID != nil) {
+                [ids addObject:sourceID];
+            }
+        }
+        CFRelease(sources);
+        const char *joined = [[ids componentsJoinedByString:@"\n"] UTF8String];
+        uint32_t required = (uint32_t)strlen(joined) + 1;
+        if (required <= len) {
+            memcpy(buf, joined, required);
+        }
+        return required;
+    }
+}
+
+// 0: selected, 1: not installed, 2: not enabled, 3: failed to select.
+extern "C" int MacSelectInputSource(const char *sourceID) {
+    @autoreleasepool {
+        NSDictionary *filter = @{
+            (NSString *)kTISPropertyInputSourceID: [NSString stringWithUTF8String:sourceID],
+        };
+        CFArrayRef sources = TISCreateInputSourceList((CFDictionaryRef)filter, true);
+        if (sources == NULL || CFArrayGetCount(sources) == 0) {
+            if (sources != NULL) {
+                CFRelease(sources);
+            }
+            return 1;
+        }
+        TISInputSourceRef source = (TISInputSourceRef)CFArrayGetValueAtIndex(sources, 0);
+        CFBooleanRef enabled = (CFBooleanRef)TISGetInputSourceProperty(source, kTISPropertyInputSourceIsEnabled);
+        int res;
+        if (enabled == NULL || !CFBooleanGetValue(enabled)) {
+            res = 2;
+        } else {
+            res = TISSelectInputSource(source) == noErr ? 0 : 3;
+        }
+        CFRelease(sources);
+        return res;
+    }
+}
+
+// CGVirtualDisplay is private SPI of CoreGraphics, resolved with NSClassFromString
+// so that the app still launches where it is missing.
+@interface CGVirtualDisplayDescriptor : NSObject
+@property(retain, nonatomic) dispatch_queue_t queue;
+@property(retain, nonatomic) NSString *name;
+@property(nonatomic) unsigned int maxPixelsWide;
+@property(nonatomic) unsigned int maxPixelsHigh;
+@property(nonatomic) CGSize sizeInMillimeters;
+@property(nonatomic) unsigned int productID;
+@property(nonatomic) unsigned int vendorID;
+@property(nonatomic) unsigned int serialNum;
+@end
+
+@interface CGVirtualDisplayMode : NSObject
+- (instancetype)initWithWidth:(unsigned int)width height:(unsigned int)height refreshRate:(double)refreshRate;
+@end
+
+@interface CGVirtualDisplaySettings : NSObject
+@property(retain, nonatomic) NSArray *modes;
+@property(nonatomic) unsigned int hiDPI;
+@end
+
+@interface CGVirtualDisplay : NSObject
+- (instancetype)initWithDescriptor:(CGVirtualDisplayDescriptor *)descriptor;
+- (BOOL)applySettings:(CGVirtualDisplaySettings *)settings;
+@property(readonly, nonatomic) CGDirectDisplayID displayID;
+@end
+
+// Returns the retained display object and sets *displayID, NULL if the SPI is unavailable or failed.
+// width and height are in pixels, the mode is width / scale x height / scale points.
+extern "C" void *MacCreateVirtualDisplay(uint32_t width, uint32_t height, uint32_t scale, const char *name, uint32_t *displayID) {
+    @autoreleasepool {
+        Class descriptorClass = NSClassFromString(@"CGVirtualDisplayDescriptor");
+        Class modeClass = NSClassFromString(@"CGVirtualDisplayMode");
+        Class settingsClass = NSClassFromString(@"CGVirtualDisplaySettings");
+        Class displayClass = NSClassFromString(@"CGVirtualDisplay");
+        if (!descriptorClass || !modeClass || !settingsClass || !displayClass || scale == 0) {
+            return NULL;
+        }
+        CGVirtualDisplayDescriptor *descriptor = [[[descriptorClass alloc] init] autorelease];
+        descriptor.queue = dispatch_get_global_queue(QOS_CLASS_DEFAULT, 0);
+        descriptor.name = [NSString stringWithUTF8String:name];
+        descriptor.maxPixelsWide = width;
+        descriptor.maxPixelsHigh = height;
+        // About 110 ppi, only used for the physical size shown in System Settings.
+        descriptor.sizeInMillimeters = CGSizeMake(width / scale * 25.4 / 110, height / scale * 25.4 / 110);
+        descriptor.productID = 0x1234;
+        descriptor.vendorID = 0x3456;
+        descriptor.serialNum = 0x0001;
+        CGVirtualDisplay *display = [[displayClass alloc] initWithDescriptor:descriptor];
+        if (display == nil) {
+            return NULL;
+        }
+        CGVirtualDisplaySettings *settings = [[[settingsClass alloc] init] autorelease];
+        settings.hiDPI = scale > 1 ? 1 : 0;
+        settings.modes = @[[[[modeClass alloc] initWithWidth:width / scale height:height / scale refreshRate:60] autorelease]];
+        if (![display applySettings:settings] || display.displayID == kCGNullDirectDisplay) {
+            [display release];
+            return NULL;
+        }
+        *displayID = display.displayID;
+        return display;
+    }
+}
+
+// Releasing the object removes the display.
+extern "C" void MacDestroyVirtualDisplay(void *display) {
+    [(CGVirtualDisplay *)display release];
+}
@@ -6,39 +6,208 @@ use super::{CursorData, ResultType};
 use cocoa::{
     appkit::{NSApp, NSApplication, NSApplicationActivationPolicy::*},
     base::{id, nil, BOOL, NO, YES},
-    foundation::{NSDictionary, NSPoint, NSSize, NSString},
+    foundation::{NSAutoreleasePool, NSDictionary, NSInteger, NSPoint, NSSize, NSString},
 };
 use core_foundation::{
     array::{CFArrayGetCount, CFArrayGetValueAtIndex},
+    base::TCFType,
     dictionary::CFDictionaryRef,
-    string::CFStringRef,
+    string::{CFString, CFStringRef},
 };
 use core_graphics::{
     display::{kCGNullWindowID, kCGWindowListOptionOnScreenOnly, CGWindowListCopyWindowInfo},
-    window::{kCGWindowName, kCGWindowOwnerPID},
+    event::{
+        CGEvent, CGEventFlags, CGEventTapLocation, CGEventType, CGKeyCode, CGMouseButton,
+        EventField, ScrollEventUnit,
+    },
+    event_source::{CGEventSource, CGEventSourceStateID},
+    geometry::CGPoint as CGEventPoint,
+    window::{
+        kCGWindowBounds, kCGWindowLayer, kCGWindowName, kCGWindowNumber, kCGWindowOwnerName,
+        kCGWindowOwnerPID,
+    },
 };
 use hbb_common::{
     anyhow::anyhow,
     bail, log,
-    message_proto::{DisplayInfo, Resolution},
+    message_proto::{DisplayInfo, Insets, Resolution},
     sysinfo::{Pid, Process, ProcessRefreshKind, System},
 };
 use include_dir::{include_dir, Dir};
 use objc::{class, msg_send, sel, sel_impl};
-use scrap::{libc::c_void, quartz::ffi::*};
-use std::path::PathBuf;
+use scrap::{libc::c_void, quartz::ffi::*, TraitCapturer, TraitPixelBuffer};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    ffi::{CStr, CString},
+    net::{Ipv4Addr, Ipv6Addr},
+    os::raw::c_char,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 static PRIVILEGES_SCRIPTS_DIR: Dir =
     include_dir!("$CARGO_MANIFEST_DIR/src/platform/privileges_scripts");
 static mut LATEST_SEED: i32 = 0;
+// The pasteboard change count of the last image returned by `get_changed_clipboard_image()`.
+static LAST_CLIPBOARD_IMAGE_COUNT: AtomicI64 = AtomicI64::new(-1);
+static SCREEN_BLANKED: AtomicBool = AtomicBool::new(false);
+static TRIM_CURSOR_BORDER: AtomicBool = AtomicBool::new(false);
+static ALLOW_BUILTIN_DISPLAY_CHANGE: AtomicBool = AtomicBool::new(false);
+static MOUSE_RELATIVE_MODE: AtomicBool = AtomicBool::new(false);
+const CURSOR_DATA_CACHE_SIZE: usize = 32;
+// Reading defaults spawns a process, do not do it on every cursor change.
+const CURSOR_SIZE_SCALE_TTL: Duration = Duration::from_secs(5);
+// In milliseconds, System Settings allows about 150 to 5000.
+const DEFAULT_DOUBLE_CLICK_TIME: u32 = 500;
+const DOUBLE_CLICK_TIME_MIN: u32 = 50;
+const DOUBLE_CLICK_TIME_MAX: u32 = 10_000;
+const BLANK_SCREEN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const WINDOWSERVER_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+const WINDOWSERVER_WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+// Reading the cursor seed is a cheap call into WindowServer.
+const CURSOR_SEED_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const PERMISSION_POLL_MIN_INTERVAL: Duration = Duration::from_millis(200);
+const PERMISSION_POLL_MAX_INTERVAL: Duration = Duration::from_millis(1_000);
+// Posted when any app is added to or removed from the accessibility list.
+const ACCESSIBILITY_CHANGED_NOTIFICATION: &str = "com.apple.accessibility.api";
+// Posted when the user switches between Light and Dark.
+const APPEARANCE_CHANGED_NOTIFICATION: &str = "AppleInterfaceThemeChangedNotification";
+const LOCK_KEYS_CHECK_INTERVAL: Duration = Duration::from_millis(300);
+// kCGEventFlagMaskAlphaShift
+const CG_EVENT_FLAG_MASK_ALPHA_SHIFT: u64 = 0x00010000;
+// kIOHIDNumLockState
+const IOHID_NUM_LOCK_STATE: i32 = 2;
+const PERMISSION_PROMPT_COOLDOWN: Duration = Duration::from_secs(10);
+const WAKE_LOCK_RETRY_DELAY: Duration = Duration::from_millis(500);
+const SERVICE_RESTART_TIMEOUT: Duration = Duration::from_secs(10);
+// macOS fires several callbacks for one reconfiguration, e.g. one per display, wait for them to settle.
+const DISPLAY_RECONFIGURE_DEBOUNCE: Duration = Duration::from_millis(500);
+// kCGDisplayBeginConfigurationFlag
+const CG_DISPLAY_BEGIN_CONFIGURATION_FLAG: u32 = 1 << 0;
+const POST_EVENT_CHECK_TIMEOUT: Duration = Duration::from_millis(200);
+const LOCK_SCREEN_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+// The AppleScript error number of a cancelled authorization dialog.
+const OSASCRIPT_USER_CANCELED: i32 = -128;
+// CGEventKeyboardSetUnicodeString ignores the characters beyond 20 UTF-16 code units.
+const UNICODE_EVENT_MAX_LEN: usize = 20;
+const DEFAULT_PERMISSION_CACHE_TTL: Duration = Duration::from_secs(2);
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// kCGEventSourceStateHIDSystemState
+const CG_EVENT_SOURCE_STATE_HID_SYSTEM: i32 = 1;
+// kCGEventSourceStateCombinedSessionState
+const CG_EVENT_SOURCE_STATE_COMBINED_SESSION: i32 = 0;
+// kCGAnyInputEventType
+const CG_ANY_INPUT_EVENT_TYPE: u32 = !0;
+// Resolution requests are applied only after no new request comes in this period.
+const RESOLUTION_QUIET_PERIOD: Duration = Duration::from_millis(300);
+// NSBitmapImageFileTypePNG
+const NS_BITMAP_IMAGE_FILE_TYPE_PNG: u64 = 4;
+// NSBitmapImageFileTypeJPEG
+const NS_BITMAP_IMAGE_FILE_TYPE_JPEG: u64 = 3;
+const MAX_SCREENSHOT_FILE_SIZE: usize = 64 * 1024 * 1024;
+const SCREENSHOT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// `softwareupdate --list` queries Apple's servers and may take dozens of seconds.
+const SOFTWARE_UPDATE_CHECK_TTL: Duration = Duration::from_secs(3600);
+const REBOOT_PENDING_FLAG_FILES: [&str; 2] = [
+    "/var/db/.SoftwareUpdateAtLogout",
+    "/var/db/.StagedAppleUpgrade",
+];
+
+#[derive(Default)]
+struct SoftwareUpdateState {
+    checked: Option<Instant>,
+    checking: bool,
+    pending: bool,
+    restart: bool,
+}
+
+lazy_static::lazy_static! {
+    static ref SOFTWARE_UPDATE_STATE: Mutex<SoftwareUpdateState> = Default::default();
+    static ref CGS_SPACE_API: Option<CgsSpaceApi> = unsafe { CgsSpaceApi::load() };
+    static ref DISPLAY_SERVICES_API: Option<DisplayServicesApi> = unsafe { DisplayServicesApi::load() };
+    // Display id -> the brightness before privacy mode.
+    static ref PRIVACY_MODE_BRIGHTNESS: Mutex<Option<HashMap<u32, f32>>> = Default::default();
+    // Display name -> (the mode before any session changed it, number of ResolutionRestore holding it).
+    static ref ORIGINAL_MODES: Mutex<HashMap<String, (DisplayMode, usize)>> = Default::default();
+    // Display id -> the gamma before blanking.
+    static ref BLANK_SCREEN_GAMMAS: Mutex<Option<HashMap<u32, GammaTable>>> = Default::default();
+    static ref PENDING_RESOLUTIONS: Mutex<HashMap<String, PendingResolution>> = Default::default();
+    static ref ORIGINAL_DOCK_AUTOHIDE: Mutex<Option<bool>> = Default::default();
+    static ref IDLE_DISCONNECT_CALLBACK: Mutex<Option<Arc<dyn Fn() + Send + Sync>>> = Default::default();
+    static ref IDLE_DISCONNECT_EXIT: Mutex<Option<Arc<AtomicBool>>> = Default::default();
+    static ref PRIVILEGES_PROMPT: Mutex<Option<String>> = Default::default();
+    static ref INPUT_MONITORING_PROMPTED: Mutex<Option<Instant>> = Default::default();
+    static ref SCREEN_RECORDING_CACHE: Mutex<Option<(Instant, bool)>> = Default::default();
+    static ref LOCAL_NETWORK_CACHE: Mutex<Option<(Instant, bool)>> = Default::default();
+    static ref PERMISSION_CACHE_TTL: Mutex<Duration> = Mutex::new(DEFAULT_PERMISSION_CACHE_TTL);
+    static ref CAPTURE_AUDIO_SINK: Mutex<Option<Box<dyn Fn(&[f32], u32, u16) + Send>>> = Default::default();
+    // Decoded cursors, least recently used first.
+    static ref CURSOR_DATA_CACHE: Mutex<VecDeque<CursorData>> = Default::default();
+    static ref CURSOR_SIZE_SCALE: Mutex<Option<(Instant, f32)>> = Default::default();
+    static ref SLEEP_WAKE_OBSERVERS: Observers<SleepWakeEvent> = Default::default();
+    static ref SESSION_OBSERVERS: Observers<SessionEvent> = Default::default();
+}
 
 extern "C" {
     fn CGSCurrentCursorSeed() -> i32;
     fn CGEventCreate(r: *const c_void) -> *const c_void;
     fn CGEventGetLocation(e: *const c_void) -> CGPoint;
+    fn CGGetDisplayTransferByTable(
+        display: u32,
+        capacity: u32,
+        red: *mut f32,
+        green: *mut f32,
+        blue: *mut f32,
+        sample_count: *mut u32,
+    ) -> CGError;
+    fn CGSetDisplayTransferByTable(
+        display: u32,
+        sample_count: u32,
+        red: *const f32,
+        green: *const f32,
+        blue: *const f32,
+    ) -> CGError;
+    fn CGSetDisplayTransferByFormula(
+        display: u32,
+        red_min: f32,
+        red_max: f32,
+        red_gamma: f32,
+        green_min: f32,
+        green_max: f32,
+        green_gamma: f32,
+        blue_min: f32,
+        blue_max: f32,
+        blue_gamma: f32,
+    ) -> CGError;
+    fn CGDisplayGammaTableCapacity(display: u32) -> u32;
+    fn CGDisplayRestoreColorSyncSettings();
+    fn CGEventSourceSecondsSinceLastEventType(state: i32, event_type: u32) -> f64;
+    fn CGEventSourceCounterForEventType(state: i32, event_type: u32) -> u32;
+    fn CGAssociateMouseAndMouseCursorPosition(connected: BOOL) -> CGError;
+    fn CGGetActiveDisplayList(max: u32, displays: *mut u32, count: *mut u32) -> CGError;
     static kAXTrustedCheckOptionPrompt: CFStringRef;
     fn AXIsProcessTrustedWithOptions(options: CFDictionaryRef) -> BOOL;
+    fn AXUIElementCreateSystemWide() -> *const c_void;
+    fn AXUIElementCreateApplication(pid: i32) -> *const c_void;
+    fn AXUIElementCopyAttributeValue(
+        element: *const c_void,
+        attribute: CFStringRef,
+        value: *mut *const c_void,
+    ) -> i32;
     fn InputMonitoringAuthStatus(_: BOOL) -> BOOL;
+    fn InputMonitoringAccessType() -> i32;
+    fn MacRunDistributedNotificationWatcher(
+        name: *const c_char,
+        callback: extern "C" fn(context: *mut c_void),
+        context: *mut c_void,
+        exit: *const bool,
+    );
     fn IsCanScreenRecording(_: BOOL) -> BOOL;
     fn CanUseNewApiForScreenCaptureCheck() -> BOOL;
     fn MacCheckAdminAuthorization() -> BOOL;
@@ -47,12 +216,58 @@ extern "C" {
         display: u32,
         widths: *mut u32,
         heights: *mut u32,
+        rates: *mut f64,
         max: u32,
         numModes: *mut u32,
     ) -> BOOL;
     fn majorVersion() -> u32;
     fn MacGetMode(display: u32, width: *mut u32, height: *mut u32) -> BOOL;
-    fn MacSetMode(display: u32, width: u32, height: u32) -> BOOL;
+    fn MacSetMode(display: u32, width: u32, height: u32, rate: f64) -> BOOL;
+    fn MacStartBlockInput(pass_user_data: i64) -> i32;
+    fn MacRunBlockInput();
+    fn MacStopBlockInput();
+    fn MacRunLocalInputMonitor(pass_user_data: i64, exit: *const bool) -> BOOL;
+    fn MacLocalInputIdleSeconds() -> f64;
+    fn MacIsDarkMode() -> BOOL;
+    fn MacGetPlatformProperty(key: *const c_char, buf: *mut c_char, len: u32) -> BOOL;
+    fn MacWakeDisplay() -> BOOL;
+    fn MacSetCaptureAudio(enabled: BOOL, callback: CaptureAudioCallback) -> i32;
+    fn MacRunSleepWakeObserver(callback: extern "C" fn(event: i32)) -> BOOL;
+    fn MacRunSessionObserver(callback: extern "C" fn(event: i32));
+    fn MacGetCurrentInputSource(buf: *mut c_char, len: u32) -> BOOL;
+    fn MacListInputSources(buf: *mut c_char, len: u32) -> u32;
+    fn MacSelectInputSource(id: *const c_char) -> i32;
+    fn MacCreateVirtualDisplay(
+        width: u32,
+        height: u32,
+        scale: u32,
+        name: *const c_char,
+        display_id: *mut u32,
+    ) -> *mut c_void;
+    fn MacDestroyVirtualDisplay(display: *mut c_void);
+    fn CGDisplayRegisterReconfigurationCallback(
+        callback: extern "C" fn(display: u32, flags: u32, user_info: *mut c_void),
+        user_info: *mut c_void,
+    ) -> CGError;
+    fn CGDisplayRemoveReconfigurationCallback(
+        callback: extern "C" fn(display: u32, flags: u32, user_info: *mut c_void),
+        user_info: *mut c_void,
+    ) -> CGError;
+    fn MacGetAllDisplaysModes(modes: *mut MacDisplayMode, max: u32) -> u32;
+    fn MacHasGpuMux() -> BOOL;
+    fn MacGetCurrentGpu(name: *mut c_char, len: u32, low_power: *mut BOOL) -> BOOL;
+    fn MacRequestDiscreteGpu(enable: BOOL) -> BOOL;
+    fn MacGetDisplayEdid(display: u32, buf: *mut u8, len: u32) -> u32;
+    fn MacGetModifierLockState(selector: i32, state: *mut BOOL) -> BOOL;
+    fn MacGetSystemColor(which: i32, rgb: *mut u8) -> BOOL;
+    fn CGSessionCopyCurrentDictionary() -> CFDictionaryRef;
+    fn CGDisplayCopyDisplayMode(display: u32) -> *const c_void;
+    fn CGDisplayModeGetRefreshRate(mode: *const c_void) -> f64;
+    fn CGDisplayModeGetWidth(mode: *const c_void) -> usize;
+    fn CGDisplayModeGetPixelWidth(mode: *const c_void) -> usize;
+    fn CGDisplayCreateUUIDFromDisplayID(display: u32) -> *const c_void;
+    fn CFUUIDCreateString(allocator: *const c_void, uuid: *const c_void) -> CFStringRef;
+    fn CGEventSourceFlagsState(state: i32) -> u64;
 }
 
 pub fn major_version() -> u32 {
@@ -72,17 +287,237 @@ pub fn is_process_trusted(prompt: bool) -> bool {
     }
 }
 
+// After an app update, the TCC record of accessibility is still keyed to the old code signature.
+// System Settings shows it granted, but `AXIsProcessTrusted()` returns false until the user removes and re-adds the app.
+// Reading the system TCC database may fail without full disk access, false is returned in this case.
+pub fn accessibility_permission_stale() -> bool {
+    if is_process_trusted(false) {
+        return false;
+    }
+    let Some(bundle_id) = bundle_identifier() else {
+        return false;
+    };
+    let db = "/Library/Application Support/com.apple.TCC/TCC.db";
+    // `auth_value` on macOS >= 11, `allowed` on older versions.
+    for (column, granted) in [("auth_value", "2"), ("allowed", "1")] {
+        let sql = format!(
+            "SELECT {} FROM access WHERE service='kTCCServiceAccessibility' AND client='{}';",
+            column,
+            bundle_id.replace('\'', "''")
+        );
+        if let Ok(output) = std::process::Command::new("sqlite3")
+            .arg("-readonly")
+            .arg(db)
+            .arg(sql)
+            .output()
+        {
+            if output.status.success() {
+                return String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .any(|l| l.trim() == granted);
+            }
+        }
+    }
+    false
+}
+
 pub fn is_can_input_monitoring(prompt: bool) -> bool {
-    unsafe {
-        let value = if prompt { YES } else { NO };
-        InputMonitoringAuthStatus(value) == YES
+    if prompt {
+        // Callers may poll with prompt, only show the approval dialog once per cooldown.
+        let mut lock = INPUT_MONITORING_PROMPTED.lock().unwrap();
+        if !lock
+            .map(|t| t.elapsed() < PERMISSION_PROMPT_COOLDOWN)
+            .unwrap_or(false)
+        {
+            *lock = Some(Instant::now());
+            return unsafe { InputMonitoringAuthStatus(YES) == YES };
+        }
+    }
+    unsafe { InputMonitoringAuthStatus(NO) == YES }
+}
+
+pub fn reset_permission_prompt_cache() {
+    INPUT_MONITORING_PROMPTED.lock().unwrap().take();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PermissionState {
+    Granted,
+    Denied,
+    // The user has not been asked yet.
+    Unknown,
+}
+
+impl From<bool> for PermissionState {
+    fn from(granted: bool) -> Self {
+        if granted {
+            Self::Granted
+        } else {
+            Self::Denied
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PermissionStatus {
+    pub accessibility: PermissionState,
+    pub input_monitoring: PermissionState,
+    pub screen_recording: PermissionState,
+}
+
+/// Snapshot of all the permissions, without prompting.
+pub fn permission_status() -> PermissionStatus {
+    permission_status_with(is_can_screen_recording(false))
+}
+
+fn permission_status_with(screen_recording: bool) -> PermissionStatus {
+    let input_monitoring = match unsafe { InputMonitoringAccessType() } {
+        0 => PermissionState::Granted,
+        1 => PermissionState::Denied,
+        _ => PermissionState::Unknown,
+    };
+    PermissionStatus {
+        accessibility: is_process_trusted(false).into(),
+        input_monitoring,
+        screen_recording: screen_recording.into(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PermissionKind {
+    Accessibility,
+    InputMonitoring,
+    ScreenRecording,
+}
+
+impl PermissionKind {
+    fn privacy_anchor(&self) -> &'static str {
+        match self {
+            Self::Accessibility => "Privacy_Accessibility",
+            Self::InputMonitoring => "Privacy_ListenEvent",
+            Self::ScreenRecording => "Privacy_ScreenCapture",
+        }
     }
 }
 
+/// Open the privacy pane of System Settings (System Preferences before macOS 13) for `kind`.
+pub fn open_privacy_settings(kind: PermissionKind) -> ResultType<()> {
+    // System Settings of macOS 13 moved the pane into an extension.
+    let pane = if major_version() >= 13 {
+        "com.apple.settings.PrivacySecurity.extension"
+    } else {
+        "com.apple.preference.security"
+    };
+    let url = format!(
+        "x-apple.systempreferences:{}?{}",
+        pane,
+        kind.privacy_anchor()
+    );
+    let status = std::process::Command::new("open").arg(&url).status()?;
+    if !status.success() {
+        bail!("Failed to open {}: {}", url, status);
+    }
+    Ok(())
+}
+
+/// Stops the watcher on drop.
+pub struct WatchHandle(Arc<AtomicBool>);
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+extern "C" fn on_permission_notification(context: *mut c_void) {
+    let changed = unsafe { &*(context as *const AtomicBool) };
+    changed.store(true, Ordering::SeqCst);
+}
+
+/// Call `callback` when any permission changes.
+/// There is no notification for input monitoring and screen recording, they are polled,
+/// backing off to `PERMISSION_POLL_MAX_INTERVAL` while nothing changes.
+pub fn watch_permissions(callback: impl Fn(PermissionStatus) + Send + 'static) -> WatchHandle {
+    let exit = Arc::new(AtomicBool::new(false));
+    let changed = Arc::new(AtomicBool::new(false));
+    let exit_cloned = exit.clone();
+    let changed_cloned = changed.clone();
+    std::thread::spawn(move || {
+        let Ok(name) = CString::new(ACCESSIBILITY_CHANGED_NOTIFICATION) else {
+            return;
+        };
+        unsafe {
+            MacRunDistributedNotificationWatcher(
+                name.as_ptr(),
+                on_permission_notification,
+                Arc::as_ptr(&changed_cloned) as _,
+                exit_cloned.as_ptr() as *const bool,
+            );
+        }
+    });
+    let exit_cloned = exit.clone();
+    std::thread::spawn(move || {
+        // Not cached, the cache of the other callers is only invalidated on changes.
+        let mut last = permission_status_with(is_can_screen_recording_(false));
+        let mut interval = PERMISSION_POLL_MIN_INTERVAL;
+        while !exit_cloned.load(Ordering::SeqCst) {
+            let start = Instant::now();
+            while start.elapsed() < interval
+                && !changed.load(Ordering::SeqCst)
+                && !exit_cloned.load(Ordering::SeqCst)
+            {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            if exit_cloned.load(Ordering::SeqCst) {
+                break;
+            }
+            changed.store(false, Ordering::SeqCst);
+            let cur = permission_status_with(is_can_screen_recording_(false));
+            if cur != last {
+                log::info!("Permissions changed from {:?} to {:?}", last, cur);
+                reset_permission_cache();
+                callback(cur);
+                last = cur;
+                interval = PERMISSION_POLL_MIN_INTERVAL;
+            } else {
+                interval = (interval * 2).min(PERMISSION_POLL_MAX_INTERVAL);
+            }
+        }
+    });
+    WatchHandle(exit)
+}
+
 // macOS >= 10.15
 // https://stackoverflow.com/questions/56597221/detecting-screen-recording-settings-on-macos-catalina/
 // remove just one app from all the permissions: tccutil reset All com.carriez.rustdesk
 pub fn is_can_screen_recording(prompt: bool) -> bool {
+    // The UI calls it frequently, only prompting bypasses the cache.
+    if !prompt {
+        let ttl = *PERMISSION_CACHE_TTL.lock().unwrap();
+        if let Some((t, res)) = *SCREEN_RECORDING_CACHE.lock().unwrap() {
+            if t.elapsed() < ttl {
+                return res;
+            }
+        }
+    }
+    let res = is_can_screen_recording_(prompt);
+    SCREEN_RECORDING_CACHE
+        .lock()
+        .unwrap()
+        .replace((Instant::now(), res));
+    res
+}
+
+pub fn set_permission_cache_ttl(ttl: Duration) {
+    *PERMISSION_CACHE_TTL.lock().unwrap() = ttl;
+}
+
+pub fn reset_permission_cache() {
+    SCREEN_RECORDING_CACHE.lock().unwrap().take();
+    LOCAL_NETWORK_CACHE.lock().unwrap().take();
+}
+
+fn is_can_screen_recording_(prompt: bool) -> bool {
     // we got some report that we show no permission even after set it, so we try to use new api for screen recording check
     // the new api is only available on macOS >= 10.15, but on stackoverflow, some people said it works on >= 10.16 (crash on 10.15),
     // but also some said it has bug on 10.16, so we just use it on 11.0.
@@ -91,48 +526,141 @@ pub fn is_can_screen_recording(prompt: bool) -> bool {
             return IsCanScreenRecording(if prompt { YES } else { NO }) == YES;
         }
     }
-    let mut can_record_screen: bool = false;
+    is_can_screen_recording_legacy(prompt)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowInfo {
+    pub id: u32,
+    pub pid: i32,
+    pub owner_name: String,
+    // None without the screen recording permission.
+    pub title: Option<String>,
+    // (x, y, width, height) in the global display space.
+    pub bounds: (f64, f64, f64, f64),
+    pub layer: i32,
+}
+
+unsafe fn window_value(w: id, key: CFStringRef) -> id {
+    msg_send![w, objectForKey: key as id]
+}
+
+unsafe fn window_bounds_of(w: id) -> (f64, f64, f64, f64) {
+    let bounds = window_value(w, kCGWindowBounds);
+    if bounds == nil {
+        return (0., 0., 0., 0.);
+    }
+    let value = |k: &str| -> f64 {
+        let k = NSString::alloc(nil).init_str(k);
+        let v: id = msg_send![bounds, objectForKey: k];
+        let () = msg_send![k, release];
+        if v == nil {
+            0.
+        } else {
+            msg_send![v, doubleValue]
+        }
+    };
+    (value("X"), value("Y"), value("Width"), value("Height"))
+}
+
+// Windows of processes we can not access, e.g. WindowServer, which owns "Menubar" and "Backstop Menubar",
+// and the Dock, which provides the desktop picture.
+unsafe fn is_system_window_owner(pid: i32) -> bool {
+    let p: id = msg_send![
+        class!(NSRunningApplication),
+        runningApplicationWithProcessIdentifier: pid
+    ];
+    if p == nil {
+        return true;
+    }
+    let url: id = msg_send![p, executableURL];
+    let exe_name: id = msg_send![url, lastPathComponent];
+    match nsstring_to_string(exe_name) {
+        Some(name) => name == "Dock",
+        None => true,
+    }
+}
+
+/// On-screen windows from front to back, the Dock and WindowServer windows only if `include_system`.
+pub fn list_windows(include_system: bool) -> Vec<WindowInfo> {
+    let mut v = vec![];
     unsafe {
-        let our_pid: i32 = std::process::id() as _;
-        let our_pid: id = msg_send![class!(NSNumber), numberWithInteger: our_pid];
+        let pool = NSAutoreleasePool::new(nil);
         let window_list =
             CGWindowListCopyWindowInfo(kCGWindowListOptionOnScreenOnly, kCGNullWindowID);
+        let Some(_window_list_guard) = CFObject::from_create(window_list as _) else {
+            pool.drain();
+            return v;
+        };
         let n = CFArrayGetCount(window_list);
-        let dock = NSString::alloc(nil).init_str("Dock");
         for i in 0..n {
             let w: id = CFArrayGetValueAtIndex(window_list, i) as _;
-            let name: id = msg_send![w, valueForKey: kCGWindowName as id];
-            if name.is_null() {
-                continue;
-            }
-            let pid: id = msg_send![w, valueForKey: kCGWindowOwnerPID as id];
-            let is_me: BOOL = msg_send![pid, isEqual: our_pid];
-            if is_me == YES {
+            let pid = window_value(w, kCGWindowOwnerPID);
+            if pid == nil {
                 continue;
             }
             let pid: i32 = msg_send![pid, intValue];
-            let p: id = msg_send![
-                class!(NSRunningApplication),
-                runningApplicationWithProcessIdentifier: pid
-            ];
-            if p.is_null() {
-                // ignore processes we don't have access to, such as WindowServer, which manages the windows named "Menubar" and "Backstop Menubar"
+            if !include_system && is_system_window_owner(pid) {
                 continue;
             }
-            let url: id = msg_send![p, executableURL];
-            let exe_name: id = msg_send![url, lastPathComponent];
-            if exe_name.is_null() {
-                continue;
+            let number = window_value(w, kCGWindowNumber);
+            let layer = window_value(w, kCGWindowLayer);
+            v.push(WindowInfo {
+                id: if number == nil {
+                    0
+                } else {
+                    msg_send![number, unsignedIntValue]
+                },
+                pid,
+                owner_name: nsstring_to_string(window_value(w, kCGWindowOwnerName))
+                    .unwrap_or_default(),
+                title: nsstring_to_string(window_value(w, kCGWindowName)),
+                bounds: window_bounds_of(w),
+                layer: if layer == nil {
+                    0
+                } else {
+                    msg_send![layer, intValue]
+                },
+            });
+        }
+        pool.drain();
+    }
+    v
+}
+
+/// The live bounds (x, y, width, height) of the window, e.g. to follow it while capturing.
+/// The window id of `list_windows()` is kept while the window is minimized and restored.
+/// None if the window has been closed.
+pub fn window_bounds(window_id: u32) -> Option<(i32, i32, u32, u32)> {
+    // kCGWindowListOptionIncludingWindow
+    const OPTION_INCLUDING_WINDOW: u32 = 1 << 3;
+    unsafe {
+        let pool = NSAutoreleasePool::new(nil);
+        let window_list = CGWindowListCopyWindowInfo(OPTION_INCLUDING_WINDOW, window_id);
+        let res = CFObject::from_create(window_list as _).and_then(|_guard| {
+            if CFArrayGetCount(window_list) < 1 {
+                return None;
             }
-            let is_dock: BOOL = msg_send![exe_name, isEqual: dock];
-            if is_dock == YES {
-                // ignore the Dock, which provides the desktop picture
-                continue;
+            let w: id = CFArrayGetValueAtIndex(window_list, 0) as _;
+            let (x, y, width, height) = window_bounds_of(w);
+            if width <= 0. || height <= 0. {
+                return None;
             }
-            can_record_screen = true;
-            break;
-        }
+            Some((x as _, y as _, width as _, height as _))
+        });
+        pool.drain();
+        res
     }
+}
+
+// Only for macOS < 11, check if we can read the names of other apps' windows.
+// Keep it out of the fast path, it enumerates all on-screen windows.
+fn is_can_screen_recording_legacy(prompt: bool) -> bool {
+    let our_pid = std::process::id() as i32;
+    // The titles are only available with the permission.
+    let can_record_screen = list_windows(false)
+        .iter()
+        .any(|w| w.pid != our_pid && w.title.is_some());
     if !can_record_screen && prompt {
         use scrap::{Capturer, Display};
         if let Ok(d) = Display::primary() {
@@ -142,15 +670,32 @@ pub fn is_can_screen_recording(prompt: bool) -> bool {
     can_record_screen
 }
 
+/// Customize the text of the administrator authorization dialog of install, uninstall and elevate,
+/// e.g. for enterprise branding. `None` restores the default text.
+pub fn set_privileges_prompt(prompt: Option<String>) {
+    *PRIVILEGES_PROMPT.lock().unwrap() = prompt.filter(|p| !p.is_empty());
+}
+
+fn get_privileges_prompt(default: String) -> String {
+    PRIVILEGES_PROMPT.lock().unwrap().clone().unwrap_or(default)
+}
+
+// Escape a string to be embedded in an AppleScript string literal.
+fn escape_applescript_string(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
 pub fn install_service() -> bool {
     is_installed_daemon(false)
 }
 
+/// Whether the system daemon and agent are installed, a user agent only install does not count.
+/// `service_health()` tells the install modes apart.
 pub fn is_installed_daemon(prompt: bool) -> bool {
-    let daemon = format!("{}_service.plist", crate::get_full_name());
-    let agent = format!("{}_server.plist", crate::get_full_name());
-    let agent_plist_file = format!("/Library/LaunchAgents/{}", agent);
     if !prompt {
+        let daemon = format!("{}_service.plist", crate::get_full_name());
+        let agent = format!("{}_server.plist", crate::get_full_name());
+        let agent_plist_file = format!("/Library/LaunchAgents/{}", agent);
         if !std::path::Path::new(&format!("/Library/LaunchDaemons/{}", daemon)).exists() {
             return false;
         }
@@ -160,142 +705,773 @@ pub fn is_installed_daemon(prompt: bool) -> bool {
         return true;
     }
 
+    std::thread::spawn(|| {
+        if let Err(e) = install_daemon_and_agent() {
+            log::error!("Failed to install daemon and agent: {}", e);
+        }
+    });
+    false
+}
+
+/// Install the daemon and agent, wait for the osascript and the agent plist up to `timeout`.
+/// Returns true only if the installation is confirmed.
+pub fn install_service_blocking(timeout: Duration) -> ResultType<bool> {
+    let start = Instant::now();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        tx.send(install_daemon_and_agent()).ok();
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(res) => {
+            // Cancelled or failed, nothing to wait for.
+            if !res? {
+                return Ok(false);
+            }
+        }
+        Err(_) => {
+            log::warn!("Installing daemon and agent timed out");
+            return Ok(false);
+        }
+    }
+    while !is_installed_daemon(false) {
+        if start.elapsed() >= timeout {
+            return Ok(false);
+        }
+        std::thread::sleep(Duration::from_millis(300));
+    }
+    Ok(true)
+}
+
+// Blocks until the user finishes the authorization dialog, returns whether the agent plist is installed.
+fn install_daemon_and_agent() -> ResultType<bool> {
+    check_install_location()?;
+    let agent = format!("{}_server.plist", crate::get_full_name());
+    let agent_plist_file = format!("/Library/LaunchAgents/{}", agent);
+
     let Some(install_script) = PRIVILEGES_SCRIPTS_DIR.get_file("install.scpt") else {
-        return false;
+        bail!("Failed to get install.scpt");
     };
     let Some(install_script_body) = install_script.contents_utf8().map(correct_app_name) else {
-        return false;
+        bail!("Failed to read install.scpt");
     };
 
     let Some(daemon_plist) = PRIVILEGES_SCRIPTS_DIR.get_file("daemon.plist") else {
-        return false;
+        bail!("Failed to get daemon.plist");
     };
     let Some(daemon_plist_body) = daemon_plist.contents_utf8().map(correct_app_name) else {
-        return false;
+        bail!("Failed to read daemon.plist");
     };
 
     let Some(agent_plist) = PRIVILEGES_SCRIPTS_DIR.get_file("agent.plist") else {
-        return false;
+        bail!("Failed to get agent.plist");
     };
     let Some(agent_plist_body) = agent_plist.contents_utf8().map(correct_app_name) else {
-        return false;
+        bail!("Failed to read agent.plist");
     };
 
-    std::thread::spawn(move || {
-        match std::process::Command::new("osascript")
-            .arg("-e")
-            .arg(install_script_body)
-            .arg(daemon_plist_body)
-            .arg(agent_plist_body)
-            .arg(&get_active_username())
+    match std::process::Command::new("osascript")
+        .arg("-e")
+        .arg(install_script_body)
+        .arg(daemon_plist_body)
+        .arg(agent_plist_body)
+        .arg(&get_active_username())
+        .arg(get_privileges_prompt(format!(
+            "{} want to install daemon and agent",
+            crate::get_app_name()
+        )))
+        .status()
+    {
+        Err(e) => bail!("run osascript failed: {}", e),
+        // e.g. the user cancelled the authorization dialog.
+        Ok(status) if !status.success() => {
+            log::info!("Installing daemon and agent failed: {}", status);
+            return Ok(false);
+        }
+        Ok(_) => {}
+    }
+    let installed = std::path::Path::new(&agent_plist_file).exists();
+    log::info!("Agent file {} installed: {}", agent_plist_file, installed);
+    if installed {
+        // The user agent has the same label, it would be loaded instead of the system one.
+        if let Err(e) = uninstall_user_agent() {
+            log::error!("Failed to uninstall the user agent: {}", e);
+        }
+        log::info!("launch server");
+        std::process::Command::new("launchctl")
+            .args(&["load", "-w", &agent_plist_file])
             .status()
-        {
-            Err(e) => {
-                log::error!("run osascript failed: {}", e);
-            }
-            _ => {
-                let installed = std::path::Path::new(&agent_plist_file).exists();
-                log::info!("Agent file {} installed: {}", agent_plist_file, installed);
-                if installed {
-                    log::info!("launch server");
-                    std::process::Command::new("launchctl")
-                        .args(&["load", "-w", &agent_plist_file])
-                        .status()
-                        .ok();
-                }
+            .ok();
+    }
+    Ok(installed)
+}
+
+// ProgramArguments of a launchd plist, None if the plist can not be read.
+fn plist_program_arguments(path: &str) -> Option<Vec<String>> {
+    unsafe {
+        let pool = NSAutoreleasePool::new(nil);
+        let file = NSString::alloc(nil).init_str(path).autorelease();
+        let dict: id = msg_send![class!(NSDictionary), dictionaryWithContentsOfFile: file];
+        let mut res = None;
+        if dict != nil {
+            let key = NSString::alloc(nil)
+                .init_str("ProgramArguments")
+                .autorelease();
+            let args: id = msg_send![dict, objectForKey: key];
+            if args != nil {
+                let n: usize = msg_send![args, count];
+                res = Some(
+                    (0..n)
+                        .filter_map(|i| {
+                            let arg: id = msg_send![args, objectAtIndex: i];
+                            nsstring_to_string(arg)
+                        })
+                        .collect(),
+                );
             }
         }
-    });
-    false
+        pool.drain();
+        res
+    }
 }
 
-fn correct_app_name(s: &str) -> String {
-    let s = s.replace("rustdesk", &crate::get_app_name().to_lowercase());
-    let s = s.replace("RustDesk", &crate::get_app_name());
-    s
+/// The installed service plists not launching the current executable, e.g. after the app was moved.
+pub fn mismatched_service_plists() -> ResultType<Vec<PathBuf>> {
+    let exe = std::env::current_exe()?;
+    let exe = exe.to_string_lossy();
+    let daemon = format!(
+        "/Library/LaunchDaemons/{}_service.plist",
+        crate::get_full_name()
+    );
+    let agent = format!(
+        "/Library/LaunchAgents/{}_server.plist",
+        crate::get_full_name()
+    );
+    let mut mismatched = vec![];
+    for plist in [daemon, agent] {
+        let Some(args) = plist_program_arguments(&plist) else {
+            log::warn!("Failed to read ProgramArguments of {}", plist);
+            mismatched.push(PathBuf::from(plist));
+            continue;
+        };
+        let matched = match args.first().map(|a| a.as_str()) {
+            // The daemon runs the executable in a shell command.
+            Some("/bin/sh") => args
+                .last()
+                .and_then(|cmd| daemon_command_program(cmd))
+                .map(|program| program == exe)
+                .unwrap_or(false),
+            Some(program) => program == exe,
+            None => false,
+        };
+        if !matched {
+            log::warn!("{} does not launch {}: {:?}", plist, exe, args);
+            mismatched.push(PathBuf::from(plist));
+        }
+    }
+    Ok(mismatched)
 }
 
-pub fn uninstall_service(show_new_window: bool, sync: bool) -> bool {
-    // to-do: do together with win/linux about refactory start/stop service
-    if !is_installed_daemon(false) {
-        return false;
+// The program run by the shell command of daemon.plist, the first word after "then",
+// unquoted, see `correct_app_name()`.
+fn daemon_command_program(cmd: &str) -> Option<String> {
+    let rest = cmd.split("; then ").nth(1)?;
+    let mut program = String::new();
+    let mut quoted = false;
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => quoted = !quoted,
+            '\\' if !quoted => program.push(chars.next()?),
+            c if c.is_whitespace() && !quoted => break,
+            c => program.push(c),
+        }
     }
+    (!program.is_empty()).then_some(program)
+}
 
-    let Some(script_file) = PRIVILEGES_SCRIPTS_DIR.get_file("uninstall.scpt") else {
-        return false;
-    };
-    let Some(script_body) = script_file.contents_utf8().map(correct_app_name) else {
-        return false;
-    };
+/// Whether both the daemon and agent plists launch the current executable, or a reinstall is needed.
+pub fn daemon_plist_matches_exe() -> ResultType<bool> {
+    Ok(mismatched_service_plists()?.is_empty())
+}
 
-    let func = move || {
-        match std::process::Command::new("osascript")
-            .arg("-e")
-            .arg(script_body)
-            .status()
-        {
-            Err(e) => {
-                log::error!("run osascript failed: {}", e);
-            }
-            _ => {
-                let agent = format!("{}_server.plist", crate::get_full_name());
-                let agent_plist_file = format!("/Library/LaunchAgents/{}", agent);
-                let uninstalled = !std::path::Path::new(&agent_plist_file).exists();
-                log::info!(
-                    "Agent file {} uninstalled: {}",
-                    agent_plist_file,
-                    uninstalled
-                );
-                if uninstalled {
-                    if !show_new_window {
-                        let _ = crate::ipc::close_all_instances();
-                        // leave ipc a little time
-                        std::thread::sleep(std::time::Duration::from_millis(300));
-                    }
-                    crate::ipc::set_option("stop-service", "Y");
-                    std::process::Command::new("launchctl")
-                        .args(&["remove", &format!("{}_server", crate::get_full_name())])
-                        .status()
-                        .ok();
-                    if show_new_window {
-                        std::process::Command::new("open")
-                            .arg("-n")
-                            .arg(&format!("/Applications/{}.app", crate::get_app_name()))
-                            .spawn()
-                            .ok();
-                        // leave open a little time
-                        std::thread::sleep(std::time::Duration::from_millis(300));
-                    }
-                    quit_gui();
-                }
-            }
-        }
-    };
-    if sync {
-        func();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstallMode {
+    NotInstalled,
+    // Only the agent in ~/Library/LaunchAgents, for access within the user's own login session.
+    UserAgent,
+    // The daemon and agent in /Library, unattended access including the login window.
+    System,
+}
+
+fn install_mode() -> InstallMode {
+    if is_installed_daemon(false) {
+        InstallMode::System
+    } else if get_user_agent_plist_file()
+        .map(|f| f.exists())
+        .unwrap_or(false)
+    {
+        InstallMode::UserAgent
     } else {
-        std::thread::spawn(func);
+        InstallMode::NotInstalled
     }
-    true
 }
 
-pub fn get_cursor_pos() -> Option<(i32, i32)> {
-    unsafe {
-        let e = CGEventCreate(0 as _);
-        let point = CGEventGetLocation(e);
-        CFRelease(e);
-        Some((point.x as _, point.y as _))
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceState {
+    NotInstalled,
+    InstalledStopped,
+    Running,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServiceHealth {
+    pub mode: InstallMode,
+    pub state: ServiceState,
+}
+
+/// How the service is installed and whether it is running.
+pub fn service_health() -> ServiceHealth {
+    ServiceHealth {
+        mode: install_mode(),
+        state: service_state(),
     }
-    /*
-    let mut pt: NSPoint = unsafe { msg_send![class!(NSEvent), mouseLocation] };
-    let screen: id = unsafe { msg_send![class!(NSScreen), currentScreenForMouseLocation] };
-    let frame: NSRect = unsafe { msg_send![screen, frame] };
+}
+
+/// Whether the service is installed, and if any of its launchd jobs is running.
+pub fn service_state() -> ServiceState {
+    if install_mode() == InstallMode::NotInstalled {
+        return ServiceState::NotInstalled;
+    }
+    let daemon = format!("{}_service", crate::get_full_name());
+    let agent = format!("{}_server", crate::get_full_name());
+    let uid = get_active_userid();
+    let running = is_launchd_job_running(&format!("system/{}", daemon), &daemon)
+        || (!uid.is_empty() && is_launchd_job_running(&format!("gui/{}/{}", uid, agent), &agent));
+    if running {
+        ServiceState::Running
+    } else {
+        ServiceState::InstalledStopped
+    }
+}
+
+/// Reload the agent of the service without reinstalling it, no admin privileges required.
+pub fn restart_service() -> ResultType<()> {
+    let agent_plist_file = match install_mode() {
+        InstallMode::NotInstalled => bail!("The service is not installed"),
+        InstallMode::System => PathBuf::from(format!(
+            "/Library/LaunchAgents/{}_server.plist",
+            crate::get_full_name()
+        )),
+        InstallMode::UserAgent => match get_user_agent_plist_file() {
+            Some(f) => f,
+            None => bail!("Failed to get home directory"),
+        },
+    };
+    let plist = agent_plist_file.to_string_lossy().to_string();
+    let uid = get_active_userid();
+    if uid.is_empty() {
+        bail!("No active user");
+    }
+    let domain = format!("gui/{}", uid);
+    let launchctl = |args: &[&str]| {
+        std::process::Command::new("launchctl")
+            .args(args)
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false)
+    };
+    // `bootout` and `bootstrap` are reliable since macOS 11.
+    let loaded = if major_version() >= 11 {
+        // Fails if it is not loaded, nothing to do then.
+        launchctl(&["bootout", &domain, &plist]);
+        launchctl(&["bootstrap", &domain, &plist])
+    } else {
+        launchctl(&["unload", &plist]);
+        launchctl(&["load", "-w", &plist])
+    };
+    if !loaded {
+        bail!("Failed to load {}", plist);
+    }
+    let start = Instant::now();
+    while start.elapsed() < SERVICE_RESTART_TIMEOUT {
+        if service_state() == ServiceState::Running {
+            log::info!("Service restarted");
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(300));
+    }
+    bail!("The service is not running after restart");
+}
+
+// `launchctl print` is not available before macOS 10.10 and its format is not stable,
+// fall back to `launchctl list`, which only sees the jobs of the caller's domain.
+fn is_launchd_job_running(target: &str, label: &str) -> bool {
+    if let Ok(output) = std::process::Command::new("launchctl")
+        .args(["print", target])
+        .output()
+    {
+        if output.status.success() {
+            let out = String::from_utf8_lossy(&output.stdout);
+            return out.lines().any(|l| {
+                let l = l.trim();
+                l == "state = running"
+                    || l.strip_prefix("pid = ")
+                        .map(|pid| pid.trim().parse::<u32>().is_ok())
+                        .unwrap_or(false)
+            });
+        }
+    }
+    if let Ok(output) = std::process::Command::new("launchctl")
+        .args(["list", label])
+        .output()
+    {
+        if output.status.success() {
+            // "PID" = 123;
+            return String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|l| l.trim().starts_with("\"PID\""));
+        }
+    }
+    false
+}
+
+fn get_user_agent_plist_file() -> Option<PathBuf> {
+    let home = std::env::var("HOME").ok()?;
+    Some(
+        PathBuf::from(home)
+            .join("Library/LaunchAgents")
+            .join(format!("{}_server.plist", crate::get_full_name())),
+    )
+}
+
+/// Install only the server agent for the current user, no admin privileges required.
+pub fn install_user_agent() -> ResultType<()> {
+    if is_installed_daemon(false) {
+        bail!("The system service is already installed");
+    }
+    check_install_location()?;
+    let Some(agent_plist_file) = get_user_agent_plist_file() else {
+        bail!("Failed to get home directory");
+    };
+    let Some(agent_plist) = PRIVILEGES_SCRIPTS_DIR.get_file("agent.plist") else {
+        bail!("Failed to get agent.plist");
+    };
+    let Some(agent_plist_body) = agent_plist.contents_utf8().map(correct_app_name) else {
+        bail!("Failed to read agent.plist");
+    };
+    // A user agent can not be loaded in the login window session.
+    let agent_plist_body = agent_plist_body.replace("<string>LoginWindow</string>", "");
+    if let Some(dir) = agent_plist_file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&agent_plist_file, agent_plist_body)?;
+    log::info!("User agent file {:?} installed", agent_plist_file);
+    let status = std::process::Command::new("launchctl")
+        .arg("load")
+        .arg("-w")
+        .arg(&agent_plist_file)
+        .status()?;
+    if !status.success() {
+        bail!("Failed to load user agent: {}", status);
+    }
+    Ok(())
+}
+
+pub fn uninstall_user_agent() -> ResultType<()> {
+    let Some(agent_plist_file) = get_user_agent_plist_file() else {
+        bail!("Failed to get home directory");
+    };
+    if !agent_plist_file.exists() {
+        return Ok(());
+    }
+    let plist = agent_plist_file.to_string_lossy().to_string();
+    let uid = get_active_userid();
+    // Fails if it is not loaded, nothing to do then.
+    if major_version() >= 11 && !uid.is_empty() {
+        std::process::Command::new("launchctl")
+            .args(["bootout", &format!("gui/{}", uid), &plist])
+            .status()
+            .ok();
+    } else {
+        std::process::Command::new("launchctl")
+            .args(["unload", "-w", &plist])
+            .status()
+            .ok();
+    }
+    std::fs::remove_file(&agent_plist_file)?;
+    log::info!("User agent file {:?} uninstalled", agent_plist_file);
+    Ok(())
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn correct_app_name(s: &str) -> String {
+    let s = s.replace("rustdesk", &crate::get_app_name().to_lowercase());
+    let s = s.replace("RustDesk", &crate::get_app_name());
+    // The scripts assume the app is installed in /Applications.
+    let default_bundle = format!("/Applications/{}.app", crate::get_app_name());
+    let bundle = bundle_path().to_string_lossy().to_string();
+    let exe = |bundle: &str| format!("{}/Contents/MacOS/{}", bundle, crate::get_app_name());
+    // The daemon runs the executable in a shell command, quote it in case the path has spaces.
+    let s = s.replace(
+        &format!("'{} --server'", exe(&default_bundle)),
+        &xml_escape(&shell_quote(&format!("{} --server", exe(&bundle)))),
+    );
+    let s = s.replace(
+        &format!("then {} ", exe(&default_bundle)),
+        &format!("then {} ", xml_escape(&shell_quote(&exe(&bundle)))),
+    );
+    s.replace(&default_bundle, &xml_escape(&bundle))
+}
+
+// The installed plists keep the path of the bundle, it must stay valid.
+fn check_install_location() -> ResultType<()> {
+    let bundle = bundle_path();
+    let path = bundle.to_string_lossy();
+    // Gatekeeper runs quarantined apps from a random read-only path.
+    if path.contains("/AppTranslocation/") {
+        bail!(
+            "{} is translocated, please move it to /Applications and open it again",
+            path
+        );
+    }
+    let c_path = CString::new(path.as_bytes())?;
+    let mut stat: scrap::libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { scrap::libc::statfs(c_path.as_ptr(), &mut stat) } == 0
+        && stat.f_flags as i64 & scrap::libc::MNT_RDONLY as i64 != 0
+    {
+        bail!(
+            "{} is on a read-only volume, e.g. a disk image, please copy it to /Applications first",
+            path
+        );
+    }
+    Ok(())
+}
+
+pub fn uninstall_service(show_new_window: bool, sync: bool) -> bool {
+    // to-do: do together with win/linux about refactory start/stop service
+    if !is_installed_daemon(false) {
+        return false;
+    }
+
+    let Some(script_file) = PRIVILEGES_SCRIPTS_DIR.get_file("uninstall.scpt") else {
+        return false;
+    };
+    let Some(script_body) = script_file.contents_utf8().map(correct_app_name) else {
+        return false;
+    };
+
+    let func = move || {
+        match std::process::Command::new("osascript")
+            .arg("-e")
+            .arg(script_body)
+            .arg(get_privileges_prompt(format!(
+                "{} want to unload daemon",
+                crate::get_app_name()
+            )))
+            .status()
+        {
+            Err(e) => {
+                log::error!("run osascript failed: {}", e);
+            }
+            _ => {
+                let agent = format!("{}_server.plist", crate::get_full_name());
+                let agent_plist_file = format!("/Library/LaunchAgents/{}", agent);
+                let uninstalled = !std::path::Path::new(&agent_plist_file).exists();
+                log::info!(
+                    "Agent file {} uninstalled: {}",
+                    agent_plist_file,
+                    uninstalled
+                );
+                if uninstalled {
+                    if !show_new_window {
+                        let _ = crate::ipc::close_all_instances();
+                        // leave ipc a little time
+                        std::thread::sleep(std::time::Duration::from_millis(300));
+                    }
+                    crate::ipc::set_option("stop-service", "Y");
+                    std::process::Command::new("launchctl")
+                        .args(&["remove", &format!("{}_server", crate::get_full_name())])
+                        .status()
+                        .ok();
+                    if show_new_window {
+                        std::process::Command::new("open")
+                            .arg("-n")
+                            .arg(bundle_path())
+                            .spawn()
+                            .ok();
+                        // leave open a little time
+                        std::thread::sleep(std::time::Duration::from_millis(300));
+                    }
+                    quit_gui();
+                }
+            }
+        }
+    };
+    if sync {
+        func();
+    } else {
+        std::thread::spawn(func);
+    }
+    true
+}
+
+// Owns a CF object returned by a Create or Copy function, released on all paths.
+struct CFObject(*const c_void);
+
+impl CFObject {
+    // None if `ptr` is null.
+    fn from_create(ptr: *const c_void) -> Option<Self> {
+        if ptr.is_null() {
+            None
+        } else {
+            Some(Self(ptr))
+        }
+    }
+
+    fn as_ptr(&self) -> *const c_void {
+        self.0
+    }
+}
+
+impl Drop for CFObject {
+    fn drop(&mut self) {
+        unsafe { CFRelease(self.0 as _) };
+    }
+}
+
+/// In the global display space: points, the top-left of the main display is the origin,
+/// so it is negative on the displays left of or above the main display.
+pub fn get_cursor_pos() -> Option<(i32, i32)> {
+    unsafe {
+        let e = CFObject::from_create(CGEventCreate(0 as _))?;
+        let point = CGEventGetLocation(e.as_ptr());
+        Some((point.x as _, point.y as _))
+    }
+    /*
+    let mut pt: NSPoint = unsafe { msg_send![class!(NSEvent), mouseLocation] };
+    let screen: id = unsafe { msg_send![class!(NSScreen), currentScreenForMouseLocation] };
+    let frame: NSRect = unsafe { msg_send![screen, frame] };
     pt.x -= frame.origin.x;
     pt.y -= frame.origin.y;
     Some((pt.x as _, pt.y as _))
     */
 }
 
+// Posting synthetic events requires the accessibility permission.
+fn check_post_event_permission() -> ResultType<()> {
+    if !is_process_trusted(false) {
+        bail!("Not allowed to post input events, the accessibility permission is not granted");
+    }
+    Ok(())
+}
+
+fn new_event_source() -> ResultType<CGEventSource> {
+    check_post_event_permission()?;
+    match CGEventSource::new(CGEventSourceStateID::CombinedSessionState) {
+        Ok(source) => Ok(source),
+        Err(_) => bail!("Failed to create event source"),
+    }
+}
+
+// Mark the event as ours, so that `block_input` lets it through.
+fn post_event(event: CGEvent) {
+    event.set_integer_value_field(
+        EventField::EVENT_SOURCE_USER_DATA,
+        enigo::ENIGO_INPUT_EXTRA_VALUE,
+    );
+    event.post(CGEventTapLocation::HID);
+}
+
+/// Whether posted events actually reach the system, by posting a mouse move to the current location.
+/// The permission APIs may report granted while the events are dropped, e.g. with a stale accessibility record.
+pub fn can_post_events() -> bool {
+    let Some((x, y)) = get_cursor_pos() else {
+        return false;
+    };
+    let Ok(source) = CGEventSource::new(CGEventSourceStateID::CombinedSessionState) else {
+        return false;
+    };
+    let Ok(event) = CGEvent::new_mouse_event(
+        source,
+        CGEventType::MouseMoved,
+        CGEventPoint::new(x as _, y as _),
+        CGMouseButton::Left,
+    ) else {
+        return false;
+    };
+    let counter = || unsafe {
+        CGEventSourceCounterForEventType(
+            CG_EVENT_SOURCE_STATE_HID_SYSTEM,
+            CGEventType::MouseMoved as _,
+        )
+    };
+    let before = counter();
+    post_event(event);
+    // The counter is updated asynchronously.
+    let start = Instant::now();
+    while start.elapsed() < POST_EVENT_CHECK_TIMEOUT {
+        if counter() != before {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    log::warn!("The posted event did not reach the system");
+    false
+}
+
+/// Post a mouse event at (x, y) in the global display space, see `get_cursor_pos()`.
+pub fn post_mouse_event(
+    kind: CGEventType,
+    x: f64,
+    y: f64,
+    button: CGMouseButton,
+) -> ResultType<()> {
+    let source = new_event_source()?;
+    let Ok(event) = CGEvent::new_mouse_event(source, kind, CGEventPoint::new(x, y), button) else {
+        bail!("Failed to create mouse event");
+    };
+    post_event(event);
+    Ok(())
+}
+
+/// Post a mouse event at (x, y) relative to the top-left of display `display_id`.
+pub fn post_mouse_event_on_display(
+    display_id: u32,
+    kind: CGEventType,
+    x: f64,
+    y: f64,
+    button: CGMouseButton,
+) -> ResultType<()> {
+    let bounds = unsafe { CGDisplayBounds(display_id) };
+    if bounds.size.width <= 0. || bounds.size.height <= 0. {
+        bail!("Display {} is not online", display_id);
+    }
+    post_mouse_event(kind, bounds.origin.x + x, bounds.origin.y + y, button)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseMode {
+    Absolute,
+    // The cursor is decoupled from the mouse, e.g. for games and 3D apps capturing the pointer.
+    Relative,
+}
+
+/// Switch between absolute and relative mouse movement, the session guard restores `Absolute` on drop.
+pub fn set_mouse_mode(mode: MouseMode) -> ResultType<()> {
+    let relative = mode == MouseMode::Relative;
+    let connected = if relative { NO } else { YES };
+    if unsafe { CGAssociateMouseAndMouseCursorPosition(connected) } != CGError::Success {
+        bail!("Failed to set mouse mode {:?}", mode);
+    }
+    MOUSE_RELATIVE_MODE.store(relative, Ordering::SeqCst);
+    log::info!("Mouse mode: {:?}", mode);
+    Ok(())
+}
+
+pub fn mouse_mode() -> MouseMode {
+    if MOUSE_RELATIVE_MODE.load(Ordering::SeqCst) {
+        MouseMode::Relative
+    } else {
+        MouseMode::Absolute
+    }
+}
+
+/// Post a mouse move of (dx, dy) without warping the cursor, for the apps reading the deltas.
+pub fn post_mouse_delta(dx: i32, dy: i32) -> ResultType<()> {
+    let source = new_event_source()?;
+    let Some((x, y)) = get_cursor_pos() else {
+        bail!("Failed to get cursor position");
+    };
+    let Ok(event) = CGEvent::new_mouse_event(
+        source,
+        CGEventType::MouseMoved,
+        CGEventPoint::new(x as _, y as _),
+        CGMouseButton::Left,
+    ) else {
+        bail!("Failed to create mouse event");
+    };
+    event.set_integer_value_field(EventField::MOUSE_EVENT_DELTA_X, dx as _);
+    event.set_integer_value_field(EventField::MOUSE_EVENT_DELTA_Y, dy as _);
+    post_event(event);
+    Ok(())
+}
+
+/// Post a scroll of (dx, dy) pixels if `pixel`, for smooth trackpad scrolling, otherwise lines.
+/// Positive dy scrolls up and positive dx scrolls left, as a physical wheel.
+/// "Natural scrolling" of the host is applied by the input devices, not to posted events,
+/// so the deltas of a client with natural scrolling must be passed as the client's device reports them.
+pub fn post_scroll(dx: f64, dy: f64, pixel: bool) -> ResultType<()> {
+    let units = if pixel {
+        ScrollEventUnit::PIXEL
+    } else {
+        ScrollEventUnit::LINE
+    };
+    let source = new_event_source()?;
+    let Ok(event) =
+        CGEvent::new_scroll_event(source, units, 2, dy.round() as i32, dx.round() as i32, 0)
+    else {
+        bail!("Failed to create scroll event");
+    };
+    if pixel {
+        // Keep the fractional part of the trackpad deltas.
+        event.set_double_value_field(EventField::SCROLL_WHEEL_EVENT_FIXED_POINT_DELTA_AXIS_1, dy);
+        event.set_double_value_field(EventField::SCROLL_WHEEL_EVENT_FIXED_POINT_DELTA_AXIS_2, dx);
+    }
+    post_event(event);
+    Ok(())
+}
+
+/// Post a key event with the modifier `flags` replacing the current ones.
+pub fn post_key_event(keycode: CGKeyCode, down: bool, flags: CGEventFlags) -> ResultType<()> {
+    let source = new_event_source()?;
+    let Ok(event) = CGEvent::new_keyboard_event(source, keycode, down) else {
+        bail!("Failed to create key event");
+    };
+    event.set_flags(flags);
+    post_event(event);
+    Ok(())
+}
+
+/// Type `text` regardless of the keyboard layout of the host.
+pub fn post_unicode_string(text: &str) -> ResultType<()> {
+    let utf16: Vec<u16> = text.encode_utf16().collect();
+    let mut start = 0;
+    while start < utf16.len() {
+        let mut end = (start + UNICODE_EVENT_MAX_LEN).min(utf16.len());
+        // Do not split a surrogate pair.
+        if end < utf16.len() && (0xD800..0xDC00).contains(&utf16[end - 1]) {
+            end -= 1;
+        }
+        for down in [true, false] {
+            let source = new_event_source()?;
+            let Ok(event) = CGEvent::new_keyboard_event(source, 0, down) else {
+                bail!("Failed to create key event");
+            };
+            event.set_string_from_utf16_unchecked(&utf16[start..end]);
+            post_event(event);
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+/// The cursor position relative to the top-left of display `display_id`, in points.
+/// None if the cursor is not on the display.
+pub fn get_cursor_pos_relative(display_id: u32) -> Option<(i32, i32)> {
+    let (x, y) = get_cursor_pos()?;
+    let bounds = unsafe { CGDisplayBounds(display_id) };
+    let (x, y) = (x as f64 - bounds.origin.x, y as f64 - bounds.origin.y);
+    if x < 0. || y < 0. || x >= bounds.size.width || y >= bounds.size.height {
+        return None;
+    }
+    Some((x as _, y as _))
+}
+
 pub fn get_focused_display(displays: Vec<DisplayInfo>) -> Option<usize> {
     unsafe {
         let main_screen: id = msg_send![class!(NSScreen), mainScreen];
@@ -322,10 +1498,52 @@ pub fn get_cursor() -> ResultType<Option<u64>> {
     Ok(Some(c.1))
 }
 
+/// Stops the cursor watcher on drop.
+pub struct CursorSubscription(Arc<AtomicBool>);
+
+impl Drop for CursorSubscription {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Call `callback` with the new `hcursor` every time the cursor image changes.
+/// Cursor seed changes which do not change the image are filtered out.
+pub fn subscribe_cursor_changes(callback: impl Fn(u64) + Send + 'static) -> CursorSubscription {
+    let exit = Arc::new(AtomicBool::new(false));
+    let exit_cloned = exit.clone();
+    std::thread::spawn(move || {
+        let mut last_seed = 0;
+        let mut last_hcursor = 0;
+        while !exit_cloned.load(Ordering::SeqCst) {
+            let seed = unsafe { CGSCurrentCursorSeed() };
+            if seed != last_seed {
+                last_seed = seed;
+                let pool = unsafe { NSAutoreleasePool::new(nil) };
+                match get_cursor_id() {
+                    Ok((_, hcursor)) => {
+                        if hcursor != last_hcursor {
+                            last_hcursor = hcursor;
+                            callback(hcursor);
+                        }
+                    }
+                    Err(e) => {
+                        log::debug!("Failed to get cursor: {}", e);
+                    }
+                }
+                unsafe { pool.drain() };
+            }
+            std::thread::sleep(CURSOR_SEED_POLL_INTERVAL);
+        }
+    });
+    CursorSubscription(exit)
+}
+
 pub fn reset_input_cache() {
     unsafe {
         LATEST_SEED = 0;
     }
+    reset_cursor_cache();
 }
 
 fn get_cursor_id() -> ResultType<(id, u64)> {
@@ -348,21 +1566,19 @@ fn get_cursor_id() -> ResultType<(id, u64)> {
         if rep == nil {
             bail!("Failed to call [NSBitmapImageRep imageRepWithData]");
         }
-        let rep_size: NSSize = msg_send![rep, size];
-        let mut hcursor =
-            size.width + size.height + hotspot.x + hotspot.y + rep_size.width + rep_size.height;
-        let x = (rep_size.width * hotspot.x / size.width) as usize;
-        let y = (rep_size.height * hotspot.y / size.height) as usize;
-        for i in 0..2 {
-            let mut x2 = x + i;
-            if x2 >= rep_size.width as usize {
-                x2 = rep_size.width as usize - 1;
-            }
-            let mut y2 = y + i;
-            if y2 >= rep_size.height as usize {
-                y2 = rep_size.height as usize - 1;
-            }
-            let color: id = msg_send![rep, colorAtX:x2 y:y2];
+        let pixels_wide: NSInteger = msg_send![rep, pixelsWide];
+        let pixels_high: NSInteger = msg_send![rep, pixelsHigh];
+        if pixels_wide <= 0 || pixels_high <= 0 || size.width <= 0. || size.height <= 0. {
+            bail!("Invalid cursor image size");
+        }
+        let (pixels_wide, pixels_high) = (pixels_wide as usize, pixels_high as usize);
+        // In pixels, so the same cursor hashes the same on Retina and non-Retina.
+        let hotspot_x = hotspot.x * pixels_wide as f64 / size.width;
+        let hotspot_y = hotspot.y * pixels_high as f64 / size.height;
+        let mut hcursor = (pixels_wide + pixels_high) as f64 + hotspot_x + hotspot_y;
+        let samples = cursor_hash_samples(pixels_wide, pixels_high, hotspot_x as _, hotspot_y as _);
+        for (i, (x, y)) in samples.into_iter().enumerate() {
+            let color: id = msg_send![rep, colorAtX:x as NSInteger y:y as NSInteger];
             if color != nil {
                 let r: f64 = msg_send![color, redComponent];
                 let g: f64 = msg_send![color, greenComponent];
@@ -375,95 +1591,352 @@ fn get_cursor_id() -> ResultType<(id, u64)> {
     }
 }
 
+// The pixels at and next to the hotspot, clamped into the bitmap, the hotspot may be on the far edge.
+fn cursor_hash_samples(
+    width: usize,
+    height: usize,
+    hotspot_x: usize,
+    hotspot_y: usize,
+) -> [(usize, usize); 2] {
+    let clamp = |v: usize, max: usize| v.min(max.saturating_sub(1));
+    [0, 1].map(|i| (clamp(hotspot_x + i, width), clamp(hotspot_y + i, height)))
+}
+
 // https://github.com/stweil/OSXvnc/blob/master/OSXvnc-server/mousecursor.c
 pub fn get_cursor_data(hcursor: u64) -> ResultType<CursorData> {
-    unsafe {
-        let (c, hcursor2) = get_cursor_id()?;
-        if hcursor != hcursor2 {
-            bail!("cursor changed");
-        }
-        let hotspot: NSPoint = msg_send![c, hotSpot];
-        let img: id = msg_send![c, image];
-        let size: NSSize = msg_send![img, size];
-        let reps: id = msg_send![img, representations];
-        if reps == nil {
-            bail!("Failed to call [NSImage representations]");
-        }
-        let nreps: usize = msg_send![reps, count];
-        if nreps == 0 {
-            bail!("Get empty [NSImage representations]");
+    let cached = {
+        let mut cache = CURSOR_DATA_CACHE.lock().unwrap();
+        let found = cache.iter().position(|cd| cd.id == hcursor);
+        // Move to the back, the front is evicted first.
+        found.and_then(|i| cache.remove(i)).map(|cd| {
+            cache.push_back(cd.clone());
+            cd
+        })
+    };
+    let mut cd = match cached {
+        Some(cd) => cd,
+        None => {
+            let cd = decode_cursor_data(hcursor)?;
+            let mut cache = CURSOR_DATA_CACHE.lock().unwrap();
+            if cache.len() >= CURSOR_DATA_CACHE_SIZE {
+                cache.pop_front();
+            }
+            cache.push_back(cd.clone());
+            cd
         }
-        let rep: id = msg_send![reps, objectAtIndex: 0];
-        /*
+    };
+    scale_cursor(&mut cd, cursor_size_scale());
+    if TRIM_CURSOR_BORDER.load(Ordering::SeqCst) {
+        trim_cursor_transparent_border(&mut cd);
+    }
+    Ok(cd)
+}
+
+// NSBitmapFormat
+const NS_BITMAP_FORMAT_ALPHA_FIRST: usize = 1 << 0;
+const NS_BITMAP_FORMAT_ALPHA_NONPREMULTIPLIED: usize = 1 << 1;
+const NS_BITMAP_FORMAT_FLOATING_POINT_SAMPLES: usize = 1 << 2;
+
+#[derive(Debug, Clone, Copy)]
+struct BitmapLayout {
+    width: usize,
+    height: usize,
+    bytes_per_row: usize,
+    bits_per_pixel: usize,
+    bits_per_sample: usize,
+    samples_per_pixel: usize,
+    has_alpha: bool,
+    format: usize,
+}
+
+// Convert 8 bit meshed gray or RGB samples, with or without alpha, to non-premultiplied RGBA.
+// None if the layout is not one of them, e.g. planar, 16 bit or floating point samples.
+fn bitmap_to_rgba(data: &[u8], l: &BitmapLayout) -> Option<Vec<u8>> {
+    let spp = l.samples_per_pixel;
+    let color_samples = if l.has_alpha {
+        spp.checked_sub(1)?
+    } else {
+        spp
+    };
+    if l.bits_per_sample != 8
+        || l.bits_per_pixel < spp * 8
+        || l.bits_per_pixel % 8 != 0
+        || (color_samples != 1 && color_samples != 3)
+        || l.format & NS_BITMAP_FORMAT_FLOATING_POINT_SAMPLES != 0
+    {
+        return None;
+    }
+    let bpp = l.bits_per_pixel / 8;
+    if l.bytes_per_row < l.width * bpp || data.len() < l.bytes_per_row * l.height {
+        return None;
+    }
+    let alpha_first = l.format & NS_BITMAP_FORMAT_ALPHA_FIRST != 0;
+    let premultiplied = l.has_alpha && l.format & NS_BITMAP_FORMAT_ALPHA_NONPREMULTIPLIED == 0;
+    let mut colors = Vec::with_capacity(l.width * l.height * 4);
+    for y in 0..l.height {
+        let row = &data[y * l.bytes_per_row..];
+        for x in 0..l.width {
+            let px = &row[x * bpp..x * bpp + spp];
+            let (color, a) = match (l.has_alpha, alpha_first) {
+                (false, _) => (px, 255),
+                (true, true) => (&px[1..], px[0]),
+                (true, false) => (&px[..spp - 1], px[spp - 1]),
+            };
+            let (r, g, b) = if color_samples == 1 {
+                (color[0], color[0], color[0])
+            } else {
+                (color[0], color[1], color[2])
+            };
+            if premultiplied && a != 0 && a != 255 {
+                let unpremultiply =
+                    |c: u8| ((c as u32 * 255 + a as u32 / 2) / a as u32).min(255) as u8;
+                colors.extend_from_slice(&[
+                    unpremultiply(r),
+                    unpremultiply(g),
+                    unpremultiply(b),
+                    a,
+                ]);
+            } else {
+                colors.extend_from_slice(&[r, g, b, a]);
+            }
+        }
+    }
+    Some(colors)
+}
+
+// Read the raw buffer of the rep, much faster than `colorAtX:y:` which allocates an NSColor per pixel.
+unsafe fn rep_pixels(rep: id, width: usize, height: usize) -> Option<Vec<u8>> {
+    let is_bitmap: BOOL = msg_send![rep, isKindOfClass: class!(NSBitmapImageRep)];
+    if is_bitmap != YES {
+        return None;
+    }
+    let planar: BOOL = msg_send![rep, isPlanar];
+    if planar == YES {
+        return None;
+    }
+    let data: *const u8 = msg_send![rep, bitmapData];
+    if data.is_null() {
+        return None;
+    }
+    let bytes_per_row: NSInteger = msg_send![rep, bytesPerRow];
+    let bits_per_pixel: NSInteger = msg_send![rep, bitsPerPixel];
+    let bits_per_sample: NSInteger = msg_send![rep, bitsPerSample];
+    let samples_per_pixel: NSInteger = msg_send![rep, samplesPerPixel];
+    let has_alpha: BOOL = msg_send![rep, hasAlpha];
+    let format: usize = msg_send![rep, bitmapFormat];
+    let layout = BitmapLayout {
+        width,
+        height,
+        bytes_per_row: bytes_per_row.max(0) as _,
+        bits_per_pixel: bits_per_pixel.max(0) as _,
+        bits_per_sample: bits_per_sample.max(0) as _,
+        samples_per_pixel: samples_per_pixel.max(0) as _,
+        has_alpha: has_alpha == YES,
+        format,
+    };
+    let data = std::slice::from_raw_parts(data, layout.bytes_per_row * height);
+    bitmap_to_rgba(data, &layout)
+}
+
+// The slow path for the formats `bitmap_to_rgba` can't interpret.
+unsafe fn rep_pixels_by_color(rep: id, width: usize, height: usize) -> Vec<u8> {
+    let mut colors: Vec<u8> = Vec::new();
+    colors.reserve(width * height * 4);
+    // TIFF is rgb colorspace, no need to convert
+    // let cs: id = msg_send![class!(NSColorSpace), sRGBColorSpace];
+    for y in 0..height {
+        for x in 0..width {
+            let color: id = msg_send![rep, colorAtX:x as cocoa::foundation::NSInteger y:y as cocoa::foundation::NSInteger];
+            // let color: id = msg_send![color, colorUsingColorSpace: cs];
+            if color == nil {
+                continue;
+            }
+            let r: f64 = msg_send![color, redComponent];
+            let g: f64 = msg_send![color, greenComponent];
+            let b: f64 = msg_send![color, blueComponent];
+            let a: f64 = msg_send![color, alphaComponent];
+            colors.push((r * 255.) as _);
+            colors.push((g * 255.) as _);
+            colors.push((b * 255.) as _);
+            colors.push((a * 255.) as _);
+        }
+    }
+    colors
+}
+
+fn reset_cursor_cache() {
+    CURSOR_DATA_CACHE.lock().unwrap().clear();
+}
+
+fn decode_cursor_data(hcursor: u64) -> ResultType<CursorData> {
+    unsafe {
+        let (c, hcursor2) = get_cursor_id()?;
+        if hcursor != hcursor2 {
+            bail!("cursor changed");
+        }
+        let hotspot: NSPoint = msg_send![c, hotSpot];
+        let img: id = msg_send![c, image];
+        let size: NSSize = msg_send![img, size];
+        let reps: id = msg_send![img, representations];
+        if reps == nil {
+            bail!("Failed to call [NSImage representations]");
+        }
+        let nreps: usize = msg_send![reps, count];
+        if nreps == 0 {
+            bail!("Get empty [NSImage representations]");
+        }
+        let rep: id = msg_send![reps, objectAtIndex: 0];
+        /*
         let n: id = msg_send![class!(NSNumber), numberWithFloat:1.0];
         let props: id = msg_send![class!(NSDictionary), dictionaryWithObject:n forKey:NSString::alloc(nil).init_str("NSImageCompressionFactor")];
         let image_data: id = msg_send![rep, representationUsingType:2 properties:props];
         let () = msg_send![image_data, writeToFile:NSString::alloc(nil).init_str("cursor.jpg") atomically:0];
         */
-        let mut colors: Vec<u8> = Vec::new();
-        colors.reserve((size.height * size.width) as usize * 4);
-        // TIFF is rgb colorspace, no need to convert
-        // let cs: id = msg_send![class!(NSColorSpace), sRGBColorSpace];
-        for y in 0..(size.height as _) {
-            for x in 0..(size.width as _) {
-                let color: id = msg_send![rep, colorAtX:x as cocoa::foundation::NSInteger y:y as cocoa::foundation::NSInteger];
-                // let color: id = msg_send![color, colorUsingColorSpace: cs];
-                if color == nil {
-                    continue;
-                }
-                let r: f64 = msg_send![color, redComponent];
-                let g: f64 = msg_send![color, greenComponent];
-                let b: f64 = msg_send![color, blueComponent];
-                let a: f64 = msg_send![color, alphaComponent];
-                colors.push((r * 255.) as _);
-                colors.push((g * 255.) as _);
-                colors.push((b * 255.) as _);
-                colors.push((a * 255.) as _);
-            }
-        }
+        // The rep of a Retina cursor has more pixels than the points of the image.
+        let pixels_wide: NSInteger = msg_send![rep, pixelsWide];
+        let pixels_high: NSInteger = msg_send![rep, pixelsHigh];
+        let scale = if size.width > 0. && pixels_wide > 0 {
+            pixels_wide as f64 / size.width
+        } else {
+            1.
+        };
+        let colors = match rep_pixels(rep, pixels_wide as _, pixels_high as _) {
+            Some(colors) => colors,
+            None => rep_pixels_by_color(rep, pixels_wide as _, pixels_high as _),
+        };
         Ok(CursorData {
             id: hcursor,
             colors: colors.into(),
-            hotx: hotspot.x as _,
-            hoty: hotspot.y as _,
-            width: size.width as _,
-            height: size.height as _,
+            hotx: (hotspot.x * scale) as _,
+            hoty: (hotspot.y * scale) as _,
+            width: pixels_wide as _,
+            height: pixels_high as _,
+            scale: scale as _,
             ..Default::default()
         })
     }
 }
 
-fn get_active_user(t: &str) -> String {
-    if let Ok(output) = std::process::Command::new("ls")
-        .args(vec![t, "/dev/console"])
+/// Crop the fully transparent padding of the cursors returned by `get_cursor_data`.
+pub fn set_trim_cursor_transparent_border(v: bool) {
+    TRIM_CURSOR_BORDER.store(v, Ordering::SeqCst);
+}
+
+/// Crop `cd` to the bounding box of its opaque pixels and the hotspot,
+/// the hotspot may lie outside the opaque pixels, e.g. the crosshair cursor.
+pub fn trim_cursor_transparent_border(cd: &mut CursorData) {
+    let (w, h) = (cd.width.max(0) as usize, cd.height.max(0) as usize);
+    if w == 0 || h == 0 || cd.colors.len() != w * h * 4 {
+        return;
+    }
+    let (mut left, mut top, mut right, mut bottom) = (w, h, 0, 0);
+    for y in 0..h {
+        for x in 0..w {
+            if cd.colors[(y * w + x) * 4 + 3] != 0 {
+                left = left.min(x);
+                top = top.min(y);
+                right = right.max(x + 1);
+                bottom = bottom.max(y + 1);
+            }
+        }
+    }
+    if left >= right {
+        // Fully transparent.
+        return;
+    }
+    let hotx = cd.hotx.clamp(0, w as i32 - 1) as usize;
+    let hoty = cd.hoty.clamp(0, h as i32 - 1) as usize;
+    left = left.min(hotx);
+    top = top.min(hoty);
+    right = right.max(hotx + 1);
+    bottom = bottom.max(hoty + 1);
+    if left == 0 && top == 0 && right == w && bottom == h {
+        return;
+    }
+    let mut colors = Vec::with_capacity((right - left) * (bottom - top) * 4);
+    for y in top..bottom {
+        colors.extend_from_slice(&cd.colors[(y * w + left) * 4..(y * w + right) * 4]);
+    }
+    cd.colors = colors.into();
+    cd.hotx -= left as i32;
+    cd.hoty -= top as i32;
+    cd.width = (right - left) as _;
+    cd.height = (bottom - top) as _;
+}
+
+// The owner of /dev/console is the user of the active console session, root at the login window.
+// `stat` output does not depend on the locale, unlike the fields of `ls -l`.
+fn get_active_user(format: &str) -> String {
+    match std::process::Command::new("stat")
+        .args(["-f", format, "/dev/console"])
         .output()
     {
-        for line in String::from_utf8_lossy(&output.stdout).lines() {
-            if let Some(n) = line.split_whitespace().nth(2) {
-                return n.to_owned();
+        Ok(output) => {
+            let user = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+            if user.is_empty() {
+                log::debug!(
+                    "Empty active user of {}, status: {}, stderr: {}",
+                    format,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
             }
+            user
+        }
+        Err(e) => {
+            log::debug!("Failed to stat /dev/console: {}", e);
+            "".to_owned()
         }
     }
-    "".to_owned()
 }
 
 pub fn get_active_username() -> String {
-    get_active_user("-l")
+    get_active_user("%Su")
 }
 
 pub fn get_active_userid() -> String {
-    get_active_user("-n")
+    get_active_user("%u")
 }
 
-pub fn get_active_user_home() -> Option<PathBuf> {
-    let username = get_active_username();
-    if !username.is_empty() {
-        let home = PathBuf::from(format!("/Users/{}", username));
-        if home.exists() {
-            return Some(home);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HomeLookupError {
+    NoActiveUser,
+    // E.g. a network home directory, which is not under /Users.
+    PathMissing(PathBuf),
+    NotADirectory,
+}
+
+impl std::fmt::Display for HomeLookupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoActiveUser => write!(f, "No active user"),
+            Self::PathMissing(path) => write!(f, "Home directory {:?} does not exist", path),
+            Self::NotADirectory => write!(f, "Home path is not a directory"),
         }
     }
-    None
+}
+
+impl std::error::Error for HomeLookupError {}
+
+pub fn get_active_user_home_detailed() -> Result<PathBuf, HomeLookupError> {
+    let username = get_active_username();
+    if username.is_empty() {
+        return Err(HomeLookupError::NoActiveUser);
+    }
+    let home = PathBuf::from(format!("/Users/{}", username));
+    if !home.exists() {
+        return Err(HomeLookupError::PathMissing(home));
+    }
+    if !home.is_dir() {
+        return Err(HomeLookupError::NotADirectory);
+    }
+    Ok(home)
+}
+
+pub fn get_active_user_home() -> Option<PathBuf> {
+    get_active_user_home_detailed()
+        .map_err(|e| log::debug!("Failed to get the home of the active user: {}", e))
+        .ok()
 }
 
 pub fn is_prelogin() -> bool {
@@ -483,13 +1956,56 @@ pub fn run_as_user(arg: Vec<&str>) -> ResultType<Option<std::process::Child>> {
     Ok(Some(task))
 }
 
-pub fn lock_screen() {
-    std::process::Command::new(
-        "/System/Library/CoreServices/Menu Extras/User.menu/Contents/Resources/CGSession",
-    )
-    .arg("-suspend")
-    .output()
-    .ok();
+// The private lock function of login.framework, which the Lock Screen menu item calls.
+unsafe fn load_sac_lock_screen_immediate() -> Option<extern "C" fn() -> i32> {
+    use scrap::libc::{dlopen, dlsym, RTLD_LAZY};
+    let handle = dlopen(
+        b"/System/Library/PrivateFrameworks/login.framework/Versions/Current/login\0".as_ptr() as _,
+        RTLD_LAZY,
+    );
+    if handle.is_null() {
+        return None;
+    }
+    let f = dlsym(handle, b"SACLockScreenImmediate\0".as_ptr() as _);
+    if f.is_null() {
+        return None;
+    }
+    Some(std::mem::transmute(f))
+}
+
+fn wait_screen_locked(timeout: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if is_screen_locked() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    is_screen_locked()
+}
+
+/// Lock the screen with `SACLockScreenImmediate`, or the Ctrl-Cmd-Q shortcut if it is missing or did not lock.
+/// `CGSession -suspend` is not used, it is a user switch rather than a lock.
+pub fn lock_screen() -> ResultType<()> {
+    if let Some(lock) = unsafe { load_sac_lock_screen_immediate() } {
+        // The return value is undocumented, check the session state instead.
+        let res = lock();
+        if wait_screen_locked(LOCK_SCREEN_CHECK_TIMEOUT) {
+            log::info!("Locked screen with SACLockScreenImmediate");
+            return Ok(());
+        }
+        log::warn!("SACLockScreenImmediate did not lock the screen: {}", res);
+    }
+    // kVK_ANSI_Q
+    const KEY_Q: CGKeyCode = 0x0C;
+    let flags = CGEventFlags::CGEventFlagCommand | CGEventFlags::CGEventFlagControl;
+    post_key_event(KEY_Q, true, flags)?;
+    post_key_event(KEY_Q, false, flags)?;
+    if !wait_screen_locked(LOCK_SCREEN_CHECK_TIMEOUT) {
+        bail!("Failed to lock screen");
+    }
+    log::info!("Locked screen with Ctrl-Cmd-Q");
+    Ok(())
 }
 
 pub fn start_os_service() {
@@ -541,6 +2057,14 @@ pub fn start_os_service() {
         }
     });
 
+    // Right after boot, capture and input fail until the login window has a graphics session.
+    if is_prelogin() && !wait_for_windowserver(WINDOWSERVER_WAIT_TIMEOUT) {
+        log::warn!(
+            "WindowServer is not ready after {:?}",
+            WINDOWSERVER_WAIT_TIMEOUT
+        );
+    }
+
     if let Err(err) = crate::ipc::start("_service") {
         log::error!("Failed to start ipc_service: {}", err);
     }
@@ -604,12 +2128,108 @@ pub fn start_os_service() {
     */
 }
 
-pub fn toggle_blank_screen(_v: bool) {
-    // https://unix.stackexchange.com/questions/17115/disable-keyboard-mouse-temporarily
+/// Black the local displays by their gamma tables, the captured frames are not affected.
+/// Do not combine with `privacy_mode`, which fails while the screen is blanked.
+/// Displays plugged in while blanked are blanked too, and `false` restores the saved gamma tables.
+/// The system resets the gamma when the process exits, the hooks below also cover panics.
+pub fn toggle_blank_screen(v: bool) {
+    let mut lock = BLANK_SCREEN_GAMMAS.lock().unwrap();
+    if !v {
+        let Some(saved) = lock.take() else {
+            return;
+        };
+        SCREEN_BLANKED.store(false, Ordering::SeqCst);
+        for (id, g) in saved {
+            if let Err(e) = set_display_gamma(id, &g) {
+                log::error!("Failed to unblank display {}: {}", id, e);
+            }
+        }
+        log::info!("Screen unblanked");
+        return;
+    }
+    if lock.is_some() {
+        return;
+    }
+    static INSTALL_HOOKS: std::sync::Once = std::sync::Once::new();
+    INSTALL_HOOKS.call_once(|| {
+        shutdown_hooks::add_shutdown_hook(unblank_screen_at_exit);
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            unblank_screen_at_exit();
+            default_hook(info);
+        }));
+    });
+    let mut saved = HashMap::new();
+    blank_new_displays(&mut saved);
+    *lock = Some(saved);
+    SCREEN_BLANKED.store(true, Ordering::SeqCst);
+    log::info!("Screen blanked");
+    // One watcher for all the enables, it idles while unblanked.
+    static START_WATCHER: std::sync::Once = std::sync::Once::new();
+    START_WATCHER.call_once(|| {
+        std::thread::spawn(|| loop {
+            std::thread::sleep(BLANK_SCREEN_CHECK_INTERVAL);
+            let mut lock = BLANK_SCREEN_GAMMAS.lock().unwrap();
+            if let Some(saved) = lock.as_mut() {
+                // Hot-plugged displays come up with their own gamma.
+                blank_new_displays(saved);
+            }
+        });
+    });
+}
+
+fn blank_new_displays(saved: &mut HashMap<u32, GammaTable>) {
+    for display in scrap::quartz::Display::online().unwrap_or_default() {
+        let id = display.id();
+        if saved.contains_key(&id) {
+            continue;
+        }
+        let Ok(g) = display_gamma(id) else {
+            continue;
+        };
+        let err = unsafe { CGSetDisplayTransferByFormula(id, 0., 0., 1., 0., 0., 1., 0., 0., 1.) };
+        if err == CGError::Success {
+            saved.insert(id, g);
+        } else {
+            log::error!("Failed to blank display {}: {:?}", id, err);
+        }
+    }
+}
+
+// Do not lock or log here, it runs at exit or in a panic.
+extern "C" fn unblank_screen_at_exit() {
+    if SCREEN_BLANKED.swap(false, Ordering::SeqCst) {
+        unsafe { CGDisplayRestoreColorSyncSettings() };
+    }
 }
 
-pub fn block_input(_v: bool) -> (bool, String) {
-    (true, "".to_owned())
+const BLOCK_INPUT_TAP_FAILED: i32 = 0;
+const BLOCK_INPUT_TAP_CREATED: i32 = 1;
+
+/// Swallow the local keyboard and mouse events with an event tap, the injected events of peers pass through.
+pub fn block_input(v: bool) -> (bool, String) {
+    if !v {
+        unsafe { MacStopBlockInput() };
+        return (true, "".to_owned());
+    }
+    if !is_process_trusted(false) {
+        return (
+            false,
+            "Accessibility permission is required to block input".to_owned(),
+        );
+    }
+    match unsafe { MacStartBlockInput(enigo::ENIGO_INPUT_EXTRA_VALUE) } {
+        BLOCK_INPUT_TAP_FAILED => (
+            false,
+            "Failed to create the event tap, please check the Input Monitoring permission"
+                .to_owned(),
+        ),
+        BLOCK_INPUT_TAP_CREATED => {
+            std::thread::spawn(|| unsafe { MacRunBlockInput() });
+            (true, "".to_owned())
+        }
+        _ => (true, "".to_owned()),
+    }
 }
 
 pub fn is_installed() -> bool {
@@ -629,8 +2249,17 @@ pub fn quit_gui() {
 }
 
 pub fn get_double_click_time() -> u32 {
-    // to-do: https://github.com/servo/core-foundation-rs/blob/786895643140fa0ee4f913d7b4aeb0c4626b2085/cocoa/src/appkit.rs#L2823
-    500 as _
+    let secs: f64 = unsafe { msg_send![class!(NSEvent), doubleClickInterval] };
+    double_click_time_ms(secs)
+}
+
+fn double_click_time_ms(secs: f64) -> u32 {
+    let ms = (secs * 1000.).round();
+    if ms.is_finite() && (DOUBLE_CLICK_TIME_MIN..=DOUBLE_CLICK_TIME_MAX).contains(&(ms as u32)) {
+        ms as _
+    } else {
+        DEFAULT_DOUBLE_CLICK_TIME
+    }
 }
 
 pub fn hide_dock() {
@@ -668,40 +2297,93 @@ fn get_server_start_time(sys: &mut System, path: &PathBuf) -> Option<(i64, Pid)>
     None
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessRole {
+    // No flag, the main window.
+    Main,
+    Server,
+    Service,
+    Cm,
+    Tray,
+    // Any other flag, e.g. --install.
+    Other,
+}
+
+/// The role of this process, from the first launch argument.
+pub fn process_role() -> ProcessRole {
+    lazy_static::lazy_static! {
+        static ref ROLE: ProcessRole = match std::env::args().nth(1).as_deref() {
+            None => ProcessRole::Main,
+            Some("--server") => ProcessRole::Server,
+            Some("--service") => ProcessRole::Service,
+            Some("--cm") => ProcessRole::Cm,
+            Some("--tray") => ProcessRole::Tray,
+            // e.g. -psn_0_12345 passed by Finder on old macOS.
+            Some(arg) if !arg.starts_with("--") => ProcessRole::Main,
+            Some(_) => ProcessRole::Other,
+        };
+    }
+    *ROLE
+}
+
 pub fn handle_application_should_open_untitled_file() {
     hbb_common::log::debug!("icon clicked on finder");
-    let x = std::env::args().nth(1).unwrap_or_default();
-    if x == "--server" || x == "--cm" || x == "--tray" {
+    if matches!(
+        process_role(),
+        ProcessRole::Server | ProcessRole::Cm | ProcessRole::Tray
+    ) {
         std::thread::spawn(move || crate::handle_url_scheme("".to_lowercase()));
     }
 }
 
+/// Resolutions of the current refresh rate, see `display_modes` for all rates.
 pub fn resolutions(name: &str) -> Vec<Resolution> {
+    let Ok(display) = resolve_display_id(name) else {
+        return vec![];
+    };
+    let rate = current_refresh_rate(display);
+    let mut v: Vec<Resolution> = vec![];
+    for mode in display_modes(name) {
+        if mode.refresh_rate != rate {
+            continue;
+        }
+        let resolution = Resolution::from(mode);
+        if !v.contains(&resolution) {
+            v.push(resolution);
+        }
+    }
+    v
+}
+
+/// All modes of the display, the same dimensions are listed once per refresh rate, e.g. 60Hz and 120Hz.
+pub fn display_modes(name: &str) -> Vec<DisplayMode> {
     let mut v = vec![];
-    if let Ok(display) = name.parse::<u32>() {
+    if let Ok(display) = resolve_display_id(name) {
         let mut num = 0;
         unsafe {
             if YES == MacGetModeNum(display, &mut num) {
-                let (mut widths, mut heights) = (vec![0; num as _], vec![0; num as _]);
+                let (mut widths, mut heights, mut rates) =
+                    (vec![0; num as _], vec![0; num as _], vec![0.; num as _]);
                 let mut real_num = 0;
                 if YES
                     == MacGetModes(
                         display,
                         widths.as_mut_ptr(),
                         heights.as_mut_ptr(),
+                        rates.as_mut_ptr(),
                         num,
                         &mut real_num,
                     )
                 {
                     if real_num <= num {
-                        for i in 0..real_num {
-                            let resolution = Resolution {
-                                width: widths[i as usize] as _,
-                                height: heights[i as usize] as _,
-                                ..Default::default()
+                        for i in 0..real_num as usize {
+                            let mode = DisplayMode {
+                                width: widths[i] as _,
+                                height: heights[i] as _,
+                                refresh_rate: rates[i],
                             };
-                            if !v.contains(&resolution) {
-                                v.push(resolution);
+                            if !v.contains(&mode) {
+                                v.push(mode);
                             }
                         }
                     }
@@ -712,8 +2394,38 @@ pub fn resolutions(name: &str) -> Vec<Resolution> {
     v
 }
 
+fn current_refresh_rate(display: u32) -> f64 {
+    unsafe {
+        let Some(mode) = CFObject::from_create(CGDisplayCopyDisplayMode(display)) else {
+            return 0.;
+        };
+        CGDisplayModeGetRefreshRate(mode.as_ptr())
+    }
+}
+
+/// The backing scale factor of the current mode of the display, pixels per point, e.g. 2.0 on Retina.
+/// 1.0 if the display is not online.
+pub fn display_scale(display_id: u32) -> f64 {
+    unsafe {
+        let Some(mode) = CFObject::from_create(CGDisplayCopyDisplayMode(display_id)) else {
+            return 1.;
+        };
+        mode_scale(
+            CGDisplayModeGetPixelWidth(mode.as_ptr()),
+            CGDisplayModeGetWidth(mode.as_ptr()),
+        )
+    }
+}
+
+fn mode_scale(pixel_width: usize, width: usize) -> f64 {
+    if pixel_width == 0 || width == 0 {
+        return 1.;
+    }
+    pixel_width as f64 / width as f64
+}
+
 pub fn current_resolution(name: &str) -> ResultType<Resolution> {
-    let display = name.parse::<u32>().map_err(|e| anyhow!(e))?;
+    let display = resolve_display_id(name)?;
     unsafe {
         let (mut width, mut height) = (0, 0);
         if NO == MacGetMode(display, &mut width, &mut height) {
@@ -727,42 +2439,228 @@ pub fn current_resolution(name: &str) -> ResultType<Resolution> {
     }
 }
 
+/// Allow changing the resolution of the built-in display, e.g. the MacBook panel.
+/// It is disallowed by default, because it disorients the local user.
+pub fn set_allow_builtin_display_change(allow: bool) {
+    ALLOW_BUILTIN_DISPLAY_CHANGE.store(allow, Ordering::SeqCst);
+}
+
+// Any refresh rate for `change_resolution_directly_with_rate`, the current one is preferred.
+pub const ANY_REFRESH_RATE: f64 = 0.;
+
 pub fn change_resolution_directly(name: &str, width: usize, height: usize) -> ResultType<()> {
-    let display = name.parse::<u32>().map_err(|e| anyhow!(e))?;
+    change_resolution_directly_with_rate(name, width, height, ANY_REFRESH_RATE)
+}
+
+/// Change the mode of the display, e.g. force 60Hz on a 120Hz panel to reduce the encoding bandwidth.
+/// Bails with the available rates of `width`x`height` if `refresh_hz` is not one of them.
+pub fn change_resolution_directly_with_rate(
+    name: &str,
+    width: usize,
+    height: usize,
+    refresh_hz: f64,
+) -> ResultType<()> {
+    let display = resolve_display_id(name)?;
     unsafe {
-        if NO == MacSetMode(display, width as _, height as _) {
+        if CGDisplayIsBuiltin(display) != 0 && !ALLOW_BUILTIN_DISPLAY_CHANGE.load(Ordering::SeqCst)
+        {
+            bail!(
+                "BuiltinDisplayProtected: changing the resolution of the built-in display {} is not allowed",
+                display
+            );
+        }
+        if NO == MacSetMode(display, width as _, height as _, refresh_hz) {
+            if refresh_hz > ANY_REFRESH_RATE {
+                let rates: Vec<f64> = display_modes(name)
+                    .iter()
+                    .filter(|m| m.width == width && m.height == height)
+                    .map(|m| m.refresh_rate)
+                    .collect();
+                if !rates.iter().any(|r| (r - refresh_hz).abs() < 0.5) {
+                    bail!(
+                        "Refresh rate {}Hz is not available for {}x{}, available: {:?}",
+                        refresh_hz,
+                        width,
+                        height,
+                        rates
+                    );
+                }
+            }
             bail!("MacSetMode failed");
         }
     }
     Ok(())
 }
 
+struct PendingResolution {
+    width: usize,
+    height: usize,
+    requested: Instant,
+}
+
+/// Debounced `change_resolution_directly`.
+/// Rapid requests, e.g. from a client resizing its window, are coalesced and only the last one is applied,
+/// because every mode switch makes the display flicker.
+pub fn request_resolution(name: &str, width: usize, height: usize) {
+    let mut lock = PENDING_RESOLUTIONS.lock().unwrap();
+    let waiting = lock.contains_key(name);
+    lock.insert(
+        name.to_owned(),
+        PendingResolution {
+            width,
+            height,
+            requested: Instant::now(),
+        },
+    );
+    if waiting {
+        return;
+    }
+    let name = name.to_owned();
+    std::thread::spawn(move || loop {
+        let wait = {
+            let mut lock = PENDING_RESOLUTIONS.lock().unwrap();
+            let Some(p) = lock.get(&name) else {
+                return;
+            };
+            let elapsed = p.requested.elapsed();
+            if elapsed >= RESOLUTION_QUIET_PERIOD {
+                let (width, height) = (p.width, p.height);
+                lock.remove(&name);
+                drop(lock);
+                if let Err(e) = change_resolution_directly(&name, width, height) {
+                    log::error!(
+                        "Failed to change resolution of '{}' to ({},{}): {}",
+                        name,
+                        width,
+                        height,
+                        e
+                    );
+                }
+                return;
+            }
+            RESOLUTION_QUIET_PERIOD - elapsed
+        };
+        std::thread::sleep(wait);
+    });
+}
+
 pub fn check_super_user_permission() -> ResultType<bool> {
     unsafe { Ok(MacCheckAdminAuthorization() == YES) }
 }
 
-pub fn elevate(args: Vec<&str>, prompt: &str) -> ResultType<bool> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElevateOutcome {
+    Success,
+    // The user dismissed the authorization dialog, no need to show an error.
+    UserCancelled,
+    Failed { code: Option<i32> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElevateResult {
+    // The exit status of the elevated command, -128 if the user cancelled, None if unknown.
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl ElevateResult {
+    pub fn outcome(&self) -> ElevateOutcome {
+        match self.code {
+            Some(0) => ElevateOutcome::Success,
+            Some(OSASCRIPT_USER_CANCELED) => ElevateOutcome::UserCancelled,
+            code => ElevateOutcome::Failed { code },
+        }
+    }
+}
+
+impl From<&std::process::Output> for ElevateResult {
+    fn from(output: &std::process::Output) -> Self {
+        let stdout = String::from_utf8_lossy(&output.stdout)
+            .trim_end()
+            .to_owned();
+        let stderr = String::from_utf8_lossy(&output.stderr)
+            .trim_end()
+            .to_owned();
+        if output.status.success() {
+            return Self {
+                code: Some(0),
+                stdout,
+                stderr,
+            };
+        }
+        // `do shell script` fails with the stderr of the command and its exit status,
+        // "0:89: execution error: ls: /x: No such file or directory (1)"
+        // "0:89: execution error: User canceled. (-128)"
+        if let Some((message, code)) = parse_osascript_error(&stderr) {
+            return Self {
+                code: Some(code),
+                stdout,
+                stderr: message,
+            };
+        }
+        Self {
+            code: None,
+            stdout,
+            stderr,
+        }
+    }
+}
+
+fn parse_osascript_error(stderr: &str) -> Option<(String, i32)> {
+    let (_, error) = stderr.split_once("execution error: ")?;
+    let error = error.trim_end().strip_suffix(')')?;
+    let (message, code) = error.rsplit_once(" (")?;
+    Some((message.to_owned(), code.parse().ok()?))
+}
+
+pub fn elevate(args: Vec<&str>, prompt: &str) -> ResultType<ElevateOutcome> {
+    Ok(elevate_output(args, prompt)?.outcome())
+}
+
+/// Like `elevate()`, with the exit status and output of the elevated command,
+/// e.g. to show the reason of a failed installation.
+pub fn elevate_output(args: Vec<&str>, prompt: &str) -> ResultType<ElevateResult> {
+    wait_elevate(elevate_async(args, prompt)?)
+}
+
+/// Spawn the elevation without waiting for the authorization dialog.
+/// Pass the output of the child to `elevate_outcome()`.
+pub fn elevate_async(args: Vec<&str>, prompt: &str) -> ResultType<std::process::Child> {
+    spawn_elevate(args, prompt, false)
+}
+
+fn wait_elevate(child: std::process::Child) -> ResultType<ElevateResult> {
+    match child.wait_with_output() {
+        Err(e) => {
+            bail!("Failed to wait for osascript: {}", e);
+        }
+        Ok(output) => Ok(ElevateResult::from(&output)),
+    }
+}
+
+fn spawn_elevate(args: Vec<&str>, prompt: &str, detach: bool) -> ResultType<std::process::Child> {
     let cmd = std::env::current_exe()?;
     match cmd.to_str() {
         Some(cmd) => {
-            let mut cmd_with_args = cmd.to_string();
-            for arg in args {
-                cmd_with_args = format!("{} {}", cmd_with_args, arg);
-            }
-            let script = format!(
-                r#"do shell script "{}" with prompt "{}" with administrator privileges"#,
-                cmd_with_args, prompt
-            );
+            let prompt = if prompt.is_empty() {
+                get_privileges_prompt(format!("{} wants to make changes", crate::get_app_name()))
+            } else {
+                get_privileges_prompt(prompt.to_owned())
+            };
+            let script = elevate_script(cmd, &args, &prompt, detach);
             match std::process::Command::new("osascript")
                 .arg("-e")
                 .arg(script)
                 .arg(&get_active_username())
-                .status()
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped())
+                .spawn()
             {
                 Err(e) => {
                     bail!("Failed to run osascript: {}", e);
                 }
-                Ok(status) => Ok(status.success() && status.code() == Some(0)),
+                Ok(child) => Ok(child),
             }
         }
         None => {
@@ -771,24 +2669,2787 @@ pub fn elevate(args: Vec<&str>, prompt: &str) -> ResultType<bool> {
     }
 }
 
-pub struct WakeLock(Option<keepawake::AwakeHandle>);
+// Quote for `/bin/sh`, which `do shell script` runs the command with.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+// `detach` runs the command in the background, otherwise `do shell script` waits for it to exit.
+fn elevate_script(cmd: &str, args: &[&str], prompt: &str, detach: bool) -> String {
+    let mut shell = std::iter::once(cmd)
+        .chain(args.iter().copied())
+        .map(shell_quote)
+        .collect::<Vec<_>>()
+        .join(" ");
+    if detach {
+        shell.push_str(" > /dev/null 2>&1 &");
+    }
+    format!(
+        r#"do shell script "{}" with prompt "{}" with administrator privileges"#,
+        escape_applescript_string(&shell),
+        escape_applescript_string(prompt)
+    )
+}
+
+pub fn elevate_outcome(output: &std::process::Output) -> ElevateOutcome {
+    ElevateResult::from(output).outcome()
+}
+
+// For the callers not distinguishing the cancellation yet.
+pub fn elevate_bool(args: Vec<&str>, prompt: &str) -> ResultType<bool> {
+    Ok(elevate(args, prompt)? == ElevateOutcome::Success)
+}
+
+/// Re-launch the current process with the same arguments plus `extra_args` as root, then exit.
+/// Do nothing if we are already root.
+pub fn restart_as_root(extra_args: Vec<&str>) -> ResultType<()> {
+    if is_root() {
+        return Ok(());
+    }
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    args.extend(extra_args);
+    let child = spawn_elevate(
+        args,
+        &format!(
+            "{} wants to restart with administrator privileges",
+            crate::get_app_name()
+        ),
+        true,
+    )?;
+    if wait_elevate(child)?.outcome() != ElevateOutcome::Success {
+        bail!("Failed to restart as root");
+    }
+    log::info!("Restarted as root, exit current instance");
+    std::process::exit(0);
+}
+
+// Creating the assertion may fail transiently right after login, retry once.
+fn create_awake_handle(display: bool, idle: bool, sleep: bool) -> Option<keepawake::AwakeHandle> {
+    let create = || {
+        keepawake::Builder::new()
+            .display(display)
+            .idle(idle)
+            .sleep(sleep)
+            .create()
+    };
+    match create() {
+        Ok(handle) => Some(handle),
+        Err(e) => {
+            log::warn!("Failed to create wake lock, retry: {}", e);
+            std::thread::sleep(WAKE_LOCK_RETRY_DELAY);
+            match create() {
+                Ok(handle) => Some(handle),
+                Err(e) => {
+                    log::warn!("Failed to create wake lock, the Mac may sleep: {}", e);
+                    None
+                }
+            }
+        }
+    }
+}
+
+pub struct WakeLock {
+    handle: Option<keepawake::AwakeHandle>,
+    display: bool,
+    idle: bool,
+    sleep: bool,
+}
 
 impl WakeLock {
     pub fn new(display: bool, idle: bool, sleep: bool) -> Self {
-        WakeLock(
-            keepawake::Builder::new()
-                .display(display)
-                .idle(idle)
-                .sleep(sleep)
-                .create()
-                .ok(),
-        )
+        WakeLock {
+            handle: create_awake_handle(display, idle, sleep),
+            display,
+            idle,
+            sleep,
+        }
     }
 
     pub fn set_display(&mut self, display: bool) -> ResultType<()> {
-        self.0
+        self.handle
             .as_mut()
             .map(|h| h.set_display(display))
-            .ok_or(anyhow!("no AwakeHandle"))?
+            .ok_or(anyhow!("no AwakeHandle"))??;
+        self.display = display;
+        Ok(())
+    }
+
+    /// Change what is kept awake in place.
+    pub fn set(&mut self, display: bool, idle: bool, sleep: bool) -> ResultType<()> {
+        // Create the new assertions before releasing the old ones, so there is no gap to sleep.
+        let handle = keepawake::Builder::new()
+            .display(display)
+            .idle(idle)
+            .sleep(sleep)
+            .create()?;
+        self.handle.replace(handle);
+        self.display = display;
+        self.idle = idle;
+        self.sleep = sleep;
+        Ok(())
+    }
+
+    /// False if the assertion could not be created, the Mac may sleep.
+    pub fn is_active(&self) -> bool {
+        self.handle.is_some()
+    }
+
+    /// (display, idle, sleep) currently prevented, all false if no assertion was created.
+    pub fn state(&self) -> (bool, bool, bool) {
+        if self.handle.is_none() {
+            return (false, false, false);
+        }
+        (self.display, self.idle, self.sleep)
+    }
+}
+
+fn platform_property(key: &str) -> Option<String> {
+    let key = CString::new(key).ok()?;
+    let mut buf = [0 as c_char; 256];
+    unsafe {
+        if NO == MacGetPlatformProperty(key.as_ptr(), buf.as_mut_ptr(), buf.len() as _) {
+            return None;
+        }
+        let value = CStr::from_ptr(buf.as_ptr()).to_string_lossy().trim().to_owned();
+        // VMs may report a blank or all-zero serial number.
+        if value.chars().all(|c| c == '0' || c == '-' || c.is_whitespace()) {
+            return None;
+        }
+        Some(value)
+    }
+}
+
+/// A stable per-machine identifier, the sha256 of the hardware serial number and platform UUID.
+/// Falls back to the MAC address if both are unavailable, e.g. in some VMs.
+pub fn host_fingerprint() -> String {
+    let serial = platform_property("IOPlatformSerialNumber");
+    let uuid = platform_property("IOPlatformUUID");
+    let mut hasher = Sha256::new();
+    if serial.is_none() && uuid.is_none() {
+        log::warn!("No serial number or platform UUID, use MAC address for host fingerprint");
+        if let Some((_, mac)) = mac_addresses().first() {
+            hasher.update(mac);
+        }
+    } else {
+        hasher.update(serial.unwrap_or_default());
+        hasher.update(uuid.unwrap_or_default());
+    }
+    hex::encode(hasher.finalize())
+}
+
+// Returns (pending, restart) of the last check, and starts a new check in background if the cached one expired.
+fn software_update_state() -> (bool, bool) {
+    let mut lock = SOFTWARE_UPDATE_STATE.lock().unwrap();
+    let expired = lock
+        .checked
+        .map(|t| t.elapsed() > SOFTWARE_UPDATE_CHECK_TTL)
+        .unwrap_or(true);
+    if expired && !lock.checking {
+        lock.checking = true;
+        std::thread::spawn(|| {
+            let res = std::process::Command::new("softwareupdate")
+                .arg("--list")
+                .output()
+                .map(|o| {
+                    parse_software_update_list(&format!(
+                        "{}{}",
+                        String::from_utf8_lossy(&o.stdout),
+                        String::from_utf8_lossy(&o.stderr)
+                    ))
+                });
+            let mut lock = SOFTWARE_UPDATE_STATE.lock().unwrap();
+            match res {
+                Ok((pending, restart)) => {
+                    lock.pending = pending;
+                    lock.restart = restart;
+                }
+                Err(e) => {
+                    log::error!("run softwareupdate failed: {}", e);
+                }
+            }
+            lock.checked = Some(Instant::now());
+            lock.checking = false;
+        });
+    }
+    (lock.pending, lock.restart)
+}
+
+fn parse_software_update_list(output: &str) -> (bool, bool) {
+    let mut pending = false;
+    let mut restart = false;
+    for line in output.lines() {
+        let line = line.trim();
+        // "* Label: ..." on macOS >= 10.15, "* <name>" on older versions.
+        if line.starts_with("* ") {
+            pending = true;
+        }
+        // "Action: restart" on macOS >= 10.15, "[restart]" on older versions.
+        if line.contains("Action: restart") || line.contains("[restart]") {
+            restart = true;
+        }
+    }
+    (pending, restart)
+}
+
+/// Whether a system software update is available, the result is cached for an hour.
+/// Returns false until the first background check is done.
+pub fn pending_system_update() -> bool {
+    software_update_state().0
+}
+
+/// Whether the system has staged an update which requires a reboot.
+pub fn reboot_required() -> bool {
+    if REBOOT_PENDING_FLAG_FILES
+        .iter()
+        .any(|f| std::path::Path::new(f).exists())
+    {
+        return true;
+    }
+    software_update_state().1
+}
+
+/// The change count of the general pasteboard, it increases every time the pasteboard owner changes.
+/// It is shared by all processes of the login session and never decreases,
+/// compare it with the last value to avoid re-reading unchanged clipboard contents.
+pub fn clipboard_change_count() -> i64 {
+    unsafe {
+        let pb: id = msg_send![class!(NSPasteboard), generalPasteboard];
+        if pb == nil {
+            return 0;
+        }
+        let count: NSInteger = msg_send![pb, changeCount];
+        count as _
+    }
+}
+
+unsafe fn nsdata_to_vec(data: id) -> Vec<u8> {
+    let len: usize = msg_send![data, length];
+    let bytes: *const u8 = msg_send![data, bytes];
+    if bytes.is_null() || len == 0 {
+        return vec![];
+    }
+    std::slice::from_raw_parts(bytes, len).to_vec()
+}
+
+/// Read the clipboard image as PNG, TIFF images are converted to PNG.
+/// Converting large images is slow, please do not call it on the main thread.
+pub fn read_clipboard_image() -> Option<Vec<u8>> {
+    unsafe {
+        let pool = NSAutoreleasePool::new(nil);
+        let res = read_clipboard_image_();
+        pool.drain();
+        res
+    }
+}
+
+unsafe fn read_clipboard_image_() -> Option<Vec<u8>> {
+    let pb: id = msg_send![class!(NSPasteboard), generalPasteboard];
+    if pb == nil {
+        return None;
+    }
+    let png_type = NSString::alloc(nil).init_str("public.png").autorelease();
+    let data: id = msg_send![pb, dataForType: png_type];
+    if data != nil {
+        return Some(nsdata_to_vec(data));
+    }
+    let tiff_type = NSString::alloc(nil).init_str("public.tiff").autorelease();
+    let data: id = msg_send![pb, dataForType: tiff_type];
+    if data == nil {
+        return None;
+    }
+    let rep: id = msg_send![class!(NSBitmapImageRep), imageRepWithData: data];
+    if rep == nil {
+        log::error!("Failed to call [NSBitmapImageRep imageRepWithData]");
+        return None;
+    }
+    let props: id = msg_send![class!(NSDictionary), dictionary];
+    let png: id = msg_send![rep, representationUsingType: NS_BITMAP_IMAGE_FILE_TYPE_PNG properties: props];
+    if png == nil {
+        log::error!("Failed to convert clipboard image to PNG");
+        return None;
+    }
+    Some(nsdata_to_vec(png))
+}
+
+/// Write PNG bytes to the general pasteboard as an image.
+pub fn write_clipboard_image(png_bytes: &[u8]) -> ResultType<()> {
+    unsafe {
+        let pool = NSAutoreleasePool::new(nil);
+        let res = write_clipboard_image_(png_bytes);
+        pool.drain();
+        res
+    }
+}
+
+unsafe fn write_clipboard_image_(png_bytes: &[u8]) -> ResultType<()> {
+    let data: id = msg_send![class!(NSData), dataWithBytes: png_bytes.as_ptr() length: png_bytes.len()];
+    if data == nil {
+        bail!("Failed to call [NSData dataWithBytes]");
+    }
+    let img: id = msg_send![class!(NSImage), alloc];
+    let img: id = msg_send![img, initWithData: data];
+    if img == nil {
+        bail!("Invalid image data");
+    }
+    let img = img.autorelease();
+    let pb: id = msg_send![class!(NSPasteboard), generalPasteboard];
+    if pb == nil {
+        bail!("Failed to call [NSPasteboard generalPasteboard]");
+    }
+    let _: NSInteger = msg_send![pb, clearContents];
+    let objects: id = msg_send![class!(NSArray), arrayWithObject: img];
+    let ok: BOOL = msg_send![pb, writeObjects: objects];
+    if ok != YES {
+        bail!("Failed to call [NSPasteboard writeObjects]");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClipboardImage {
+    pub width: usize,
+    pub height: usize,
+    // Non-premultiplied RGBA.
+    pub rgba: Vec<u8>,
+}
+
+/// Read the clipboard image (PNG or TIFF) as RGBA.
+pub fn get_clipboard_image() -> Option<ClipboardImage> {
+    let png = read_clipboard_image()?;
+    unsafe {
+        let pool = NSAutoreleasePool::new(nil);
+        let res = png_to_clipboard_image(&png);
+        pool.drain();
+        res
+    }
+}
+
+/// Like `get_clipboard_image()`, but None if the pasteboard has not changed since the last image returned,
+/// so that the same image is not sent again.
+pub fn get_changed_clipboard_image() -> Option<ClipboardImage> {
+    let count = clipboard_change_count();
+    if LAST_CLIPBOARD_IMAGE_COUNT.load(Ordering::SeqCst) == count {
+        return None;
+    }
+    let img = get_clipboard_image()?;
+    LAST_CLIPBOARD_IMAGE_COUNT.store(count, Ordering::SeqCst);
+    Some(img)
+}
+
+/// Forget the change count of the last image, the next `get_changed_clipboard_image()` reads the pasteboard.
+/// The change count itself can not be reset.
+pub fn reset_clipboard_change_count() {
+    LAST_CLIPBOARD_IMAGE_COUNT.store(-1, Ordering::SeqCst);
+}
+
+unsafe fn png_to_clipboard_image(png: &[u8]) -> Option<ClipboardImage> {
+    let data: id = msg_send![class!(NSData), dataWithBytes: png.as_ptr() length: png.len()];
+    if data == nil {
+        return None;
+    }
+    let rep: id = msg_send![class!(NSBitmapImageRep), imageRepWithData: data];
+    if rep == nil {
+        log::error!("Failed to call [NSBitmapImageRep imageRepWithData]");
+        return None;
+    }
+    let width: NSInteger = msg_send![rep, pixelsWide];
+    let height: NSInteger = msg_send![rep, pixelsHigh];
+    if width <= 0 || height <= 0 {
+        return None;
+    }
+    let (width, height) = (width as usize, height as usize);
+    let rgba =
+        rep_pixels(rep, width, height).unwrap_or_else(|| rep_pixels_by_color(rep, width, height));
+    if rgba.len() != width * height * 4 {
+        return None;
+    }
+    Some(ClipboardImage {
+        width,
+        height,
+        rgba,
+    })
+}
+
+/// Write the RGBA image to the general pasteboard.
+pub fn set_clipboard_image(img: &ClipboardImage) -> ResultType<()> {
+    if img.width == 0 || img.height == 0 || img.rgba.len() != img.width * img.height * 4 {
+        bail!("Invalid image size {}x{}", img.width, img.height);
+    }
+    unsafe {
+        let pool = NSAutoreleasePool::new(nil);
+        let res = set_clipboard_image_(img);
+        pool.drain();
+        res
+    }
+}
+
+// An autoreleased NSBitmapImageRep holding a copy of the RGBA pixels.
+unsafe fn rgba_bitmap_rep(width: usize, height: usize, rgba: &[u8]) -> ResultType<id> {
+    if rgba.len() != width * height * 4 {
+        bail!("Invalid image size {}x{}", width, height);
+    }
+    let rep: id = msg_send![class!(NSBitmapImageRep), alloc];
+    let color_space = NSString::alloc(nil)
+        .init_str("NSDeviceRGBColorSpace")
+        .autorelease();
+    let planes: *mut *mut u8 = std::ptr::null_mut();
+    let rep: id = msg_send![rep, initWithBitmapDataPlanes: planes
+        pixelsWide: width as NSInteger
+        pixelsHigh: height as NSInteger
+        bitsPerSample: 8 as NSInteger
+        samplesPerPixel: 4 as NSInteger
+        hasAlpha: YES
+        isPlanar: NO
+        colorSpaceName: color_space
+        bitmapFormat: NS_BITMAP_FORMAT_ALPHA_NONPREMULTIPLIED as u64
+        bytesPerRow: (width * 4) as NSInteger
+        bitsPerPixel: 32 as NSInteger];
+    if rep == nil {
+        bail!("Failed to create NSBitmapImageRep");
+    }
+    let rep = rep.autorelease();
+    let data: *mut u8 = msg_send![rep, bitmapData];
+    if data.is_null() {
+        bail!("Failed to get the bitmap data");
+    }
+    std::ptr::copy_nonoverlapping(rgba.as_ptr(), data, rgba.len());
+    Ok(rep)
+}
+
+unsafe fn set_clipboard_image_(img: &ClipboardImage) -> ResultType<()> {
+    let rep = rgba_bitmap_rep(img.width, img.height, &img.rgba)?;
+    let props: id = msg_send![class!(NSDictionary), dictionary];
+    let png: id =
+        msg_send![rep, representationUsingType: NS_BITMAP_IMAGE_FILE_TYPE_PNG properties: props];
+    if png == nil {
+        bail!("Failed to convert the image to PNG");
+    }
+    write_clipboard_image_(&nsdata_to_vec(png))
+}
+
+unsafe fn nsstring_to_string(s: id) -> Option<String> {
+    if s == nil {
+        return None;
+    }
+    let bytes: *const c_char = msg_send![s, UTF8String];
+    if bytes.is_null() {
+        return None;
+    }
+    Some(CStr::from_ptr(bytes).to_string_lossy().into_owned())
+}
+
+pub fn bundle_identifier() -> Option<String> {
+    unsafe {
+        let bundle: id = msg_send![class!(NSBundle), mainBundle];
+        if bundle == nil {
+            return None;
+        }
+        let bundle_id: id = msg_send![bundle, bundleIdentifier];
+        nsstring_to_string(bundle_id)
+    }
+}
+
+// The CGS space functions are private SPI, they are not documented and may change or disappear in any macOS release.
+// So they are resolved at runtime instead of being linked.
+struct CgsSpaceApi {
+    main_connection_id: extern "C" fn() -> i32,
+    get_active_space: extern "C" fn(i32) -> u64,
+    copy_managed_display_spaces: extern "C" fn(i32) -> id,
+}
+
+impl CgsSpaceApi {
+    unsafe fn load() -> Option<Self> {
+        use scrap::libc::{dlsym, RTLD_DEFAULT};
+        let main_connection_id = dlsym(RTLD_DEFAULT, b"CGSMainConnectionID\0".as_ptr() as _);
+        let get_active_space = dlsym(RTLD_DEFAULT, b"CGSGetActiveSpace\0".as_ptr() as _);
+        let copy_managed_display_spaces =
+            dlsym(RTLD_DEFAULT, b"CGSCopyManagedDisplaySpaces\0".as_ptr() as _);
+        if main_connection_id.is_null()
+            || get_active_space.is_null()
+            || copy_managed_display_spaces.is_null()
+        {
+            log::warn!("CGS space functions are unavailable");
+            return None;
+        }
+        Some(Self {
+            main_connection_id: std::mem::transmute(main_connection_id),
+            get_active_space: std::mem::transmute(get_active_space),
+            copy_managed_display_spaces: std::mem::transmute(copy_managed_display_spaces),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SpaceInfo {
+    pub id: u64,
+    // The display UUID, or "Main" if displays have separate spaces disabled.
+    pub display: String,
+    pub is_current: bool,
+    pub is_fullscreen: bool,
+}
+
+/// The id of the active space, 0 if unavailable.
+pub fn current_space_id() -> u64 {
+    let Some(api) = CGS_SPACE_API.as_ref() else {
+        return 0;
+    };
+    (api.get_active_space)((api.main_connection_id)())
+}
+
+/// All spaces of all displays, empty if the SPI is unavailable.
+pub fn list_spaces() -> Vec<SpaceInfo> {
+    let mut v = vec![];
+    let Some(api) = CGS_SPACE_API.as_ref() else {
+        return v;
+    };
+    unsafe {
+        let displays = (api.copy_managed_display_spaces)((api.main_connection_id)());
+        let Some(_displays_guard) = CFObject::from_create(displays as _) else {
+            return v;
+        };
+        let key = |k: &str| NSString::alloc(nil).init_str(k).autorelease();
+        let pool = NSAutoreleasePool::new(nil);
+        let ndisplays: usize = msg_send![displays, count];
+        for i in 0..ndisplays {
+            let display: id = msg_send![displays, objectAtIndex: i];
+            let display_id: id = msg_send![display, objectForKey: key("Display Identifier")];
+            let display_id = nsstring_to_string(display_id).unwrap_or_default();
+            let current: id = msg_send![display, objectForKey: key("Current Space")];
+            let current_id: u64 = if current != nil {
+                let n: id = msg_send![current, objectForKey: key("id64")];
+                if n != nil {
+                    msg_send![n, unsignedLongLongValue]
+                } else {
+                    0
+                }
+            } else {
+                0
+            };
+            let spaces: id = msg_send![display, objectForKey: key("Spaces")];
+            if spaces == nil {
+                continue;
+            }
+            let nspaces: usize = msg_send![spaces, count];
+            for j in 0..nspaces {
+                let space: id = msg_send![spaces, objectAtIndex: j];
+                let n: id = msg_send![space, objectForKey: key("id64")];
+                if n == nil {
+                    continue;
+                }
+                let id: u64 = msg_send![n, unsignedLongLongValue];
+                let t: id = msg_send![space, objectForKey: key("type")];
+                // 0: user space, 4: fullscreen space
+                let t: i32 = if t != nil { msg_send![t, intValue] } else { 0 };
+                v.push(SpaceInfo {
+                    id,
+                    display: display_id.clone(),
+                    is_current: id == current_id,
+                    is_fullscreen: t == 4,
+                });
+            }
+        }
+        pool.drain();
+    }
+    v
+}
+
+/// Actively wake an asleep display, so that capture gets a live framebuffer on session start.
+/// Unlike `WakeLock`, which only prevents sleeping from now on.
+pub fn wake_display() -> ResultType<()> {
+    if unsafe { MacWakeDisplay() } == YES {
+        return Ok(());
+    }
+    log::warn!("IOPMAssertionDeclareUserActivity failed, try caffeinate");
+    let status = std::process::Command::new("caffeinate")
+        .args(&["-u", "-t", "1"])
+        .status()?;
+    if !status.success() {
+        bail!("caffeinate failed: {}", status);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NetInterface {
+    pub name: String,
+    pub is_up: bool,
+    pub is_loopback: bool,
+    // Whether the default route goes through this interface.
+    pub is_default: bool,
+    pub ipv4: Vec<Ipv4Addr>,
+    pub ipv6: Vec<Ipv6Addr>,
+}
+
+pub fn network_interfaces() -> Vec<NetInterface> {
+    use scrap::libc::{
+        freeifaddrs, getifaddrs, ifaddrs, sockaddr_in, sockaddr_in6, AF_INET, AF_INET6,
+        IFF_LOOPBACK, IFF_UP,
+    };
+    let mut interfaces: BTreeMap<String, NetInterface> = BTreeMap::new();
+    unsafe {
+        let mut addrs: *mut ifaddrs = std::ptr::null_mut();
+        if getifaddrs(&mut addrs) != 0 {
+            log::error!("getifaddrs failed: {}", std::io::Error::last_os_error());
+            return vec![];
+        }
+        let mut cur = addrs;
+        while !cur.is_null() {
+            let ifa = &*cur;
+            cur = ifa.ifa_next;
+            if ifa.ifa_name.is_null() {
+                continue;
+            }
+            let name = CStr::from_ptr(ifa.ifa_name).to_string_lossy().into_owned();
+            let interface = interfaces
+                .entry(name.clone())
+                .or_insert_with(|| NetInterface {
+                    name,
+                    ..Default::default()
+                });
+            interface.is_up |= ifa.ifa_flags & IFF_UP as u32 != 0;
+            interface.is_loopback |= ifa.ifa_flags & IFF_LOOPBACK as u32 != 0;
+            if ifa.ifa_addr.is_null() {
+                continue;
+            }
+            match (*ifa.ifa_addr).sa_family as i32 {
+                AF_INET => {
+                    let addr = &*(ifa.ifa_addr as *const sockaddr_in);
+                    interface
+                        .ipv4
+                        .push(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)));
+                }
+                AF_INET6 => {
+                    let addr = &*(ifa.ifa_addr as *const sockaddr_in6);
+                    interface.ipv6.push(Ipv6Addr::from(addr.sin6_addr.s6_addr));
+                }
+                _ => {}
+            }
+        }
+        freeifaddrs(addrs);
+    }
+    // default-net reads the routing table with sysctl.
+    if let Ok(default) = default_net::get_default_interface() {
+        if let Some(interface) = interfaces.get_mut(&default.name) {
+            interface.is_default = true;
+        }
+    }
+    interfaces.into_values().collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GatekeeperStatus {
+    // Gatekeeper assessments are disabled system wide.
+    Disabled,
+    // `source` is the reason of acceptance, e.g. "Notarized Developer ID".
+    Allowed { source: String },
+    Rejected { reason: String },
+    Unknown,
+}
+
+/// Whether Gatekeeper allows our app bundle to run, e.g. the service never starts if it is rejected.
+pub fn gatekeeper_assessment() -> GatekeeperStatus {
+    if let Ok(output) = std::process::Command::new("spctl").arg("--status").output() {
+        if String::from_utf8_lossy(&output.stdout).contains("assessments disabled") {
+            return GatekeeperStatus::Disabled;
+        }
+    }
+    let bundle = bundle_path();
+    let output = match std::process::Command::new("spctl")
+        .args(&["--assess", "--type", "execute", "-vv"])
+        .arg(&bundle)
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            log::error!("run spctl failed: {}", e);
+            return GatekeeperStatus::Unknown;
+        }
+    };
+    // spctl writes the assessment to stderr, e.g.
+    // /Applications/RustDesk.app: accepted
+    // source=Notarized Developer ID
+    let out = String::from_utf8_lossy(&output.stderr).to_string();
+    let source = out
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("source="))
+        .unwrap_or_default()
+        .to_owned();
+    if output.status.success() {
+        GatekeeperStatus::Allowed { source }
+    } else if out.contains("rejected") {
+        let reason = out
+            .lines()
+            .next()
+            .and_then(|l| l.rsplit_once(": "))
+            .map(|(_, r)| r.to_owned())
+            .unwrap_or_default();
+        GatekeeperStatus::Rejected {
+            reason: if source.is_empty() {
+                reason
+            } else {
+                format!("{} ({})", reason, source)
+            },
+        }
+    } else {
+        log::warn!("Unknown spctl output: {}", out);
+        GatekeeperStatus::Unknown
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppInfo {
+    // None for the apps without a bundle, e.g. command line tools.
+    pub bundle_id: Option<String>,
+    pub name: String,
+    pub pid: i32,
+}
+
+pub fn frontmost_app() -> Option<AppInfo> {
+    unsafe {
+        let workspace: id = msg_send![class!(NSWorkspace), sharedWorkspace];
+        if workspace == nil {
+            return None;
+        }
+        let app: id = msg_send![workspace, frontmostApplication];
+        if app == nil {
+            return None;
+        }
+        let bundle_id: id = msg_send![app, bundleIdentifier];
+        let name: id = msg_send![app, localizedName];
+        let pid: i32 = msg_send![app, processIdentifier];
+        Some(AppInfo {
+            bundle_id: nsstring_to_string(bundle_id),
+            name: nsstring_to_string(name).unwrap_or_default(),
+            pid,
+        })
+    }
+}
+
+pub fn frontmost_app_bundle_id() -> Option<String> {
+    frontmost_app()?.bundle_id
+}
+
+/// The title of the focused window of the frontmost app, None without the accessibility permission.
+pub fn focused_window_title() -> Option<String> {
+    if !is_process_trusted(false) {
+        return None;
+    }
+    let pid = frontmost_app()?.pid;
+    unsafe {
+        let element = CFObject::from_create(AXUIElementCreateApplication(pid))?;
+        let window =
+            CFObject::from_create(ax_copy_attribute(element.as_ptr(), "AXFocusedWindow")?)?;
+        let title = CFObject::from_create(ax_copy_attribute(window.as_ptr(), "AXTitle")?)?;
+        let title = title.as_ptr() as id;
+        let is_string: BOOL = msg_send![title, isKindOfClass: class!(NSString)];
+        if is_string != YES {
+            return None;
+        }
+        nsstring_to_string(title)
+    }
+}
+
+/// Whether the login window (or the lock screen, which is also owned by loginwindow) is frontmost.
+pub fn is_loginwindow_active() -> bool {
+    frontmost_app_bundle_id().as_deref() == Some("com.apple.loginwindow")
+}
+
+// Run a command in the active user's context, so that `defaults` works on the user's domain
+// even if we are running as root in service mode.
+fn active_user_command(program: &str) -> std::process::Command {
+    if is_root() {
+        let uid = get_active_userid();
+        let username = get_active_username();
+        if !uid.is_empty() && uid != "0" && !username.is_empty() {
+            let mut cmd = std::process::Command::new("launchctl");
+            cmd.args(&["asuser", &uid, "sudo", "-u", &username, program]);
+            return cmd;
+        }
+    }
+    std::process::Command::new(program)
+}
+
+fn read_user_default(domain: &str, key: &str) -> Option<String> {
+    let output = active_user_command("defaults")
+        .args(&["read", domain, key])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Whether the active user's Dock (not our Dock icon) hides automatically.
+pub fn system_dock_autohide() -> bool {
+    read_user_default("com.apple.dock", "autohide")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Toggle auto-hiding of the active user's Dock, and restart the Dock to apply it.
+/// The original setting is recorded on the first call, `restore_system_dock_autohide()` reverts it.
+pub fn set_system_dock_autohide(v: bool) -> ResultType<()> {
+    {
+        let mut original = ORIGINAL_DOCK_AUTOHIDE.lock().unwrap();
+        if original.is_none() {
+            *original = Some(system_dock_autohide());
+        }
+    }
+    write_system_dock_autohide(v)
+}
+
+pub fn restore_system_dock_autohide() -> ResultType<()> {
+    let Some(original) = ORIGINAL_DOCK_AUTOHIDE.lock().unwrap().take() else {
+        return Ok(());
+    };
+    log::info!("Restore Dock autohide to {}", original);
+    write_system_dock_autohide(original)
+}
+
+fn write_system_dock_autohide(v: bool) -> ResultType<()> {
+    let status = active_user_command("defaults")
+        .args(&[
+            "write",
+            "com.apple.dock",
+            "autohide",
+            "-bool",
+            if v { "true" } else { "false" },
+        ])
+        .status()?;
+    if !status.success() {
+        bail!("Failed to write Dock autohide: {}", status);
+    }
+    active_user_command("killall").arg("Dock").status()?;
+    Ok(())
+}
+
+// The returned value must be released by the caller.
+unsafe fn ax_copy_attribute(element: *const c_void, attribute: &str) -> Option<*const c_void> {
+    let attribute = CFString::new(attribute);
+    let mut value: *const c_void = std::ptr::null();
+    // kAXErrorSuccess
+    if AXUIElementCopyAttributeValue(element, attribute.as_concrete_TypeRef(), &mut value) != 0
+        || value.is_null()
+    {
+        return None;
+    }
+    Some(value)
+}
+
+/// The selected text of the focused UI element, requires the accessibility permission.
+pub fn selected_text() -> Option<String> {
+    if !is_process_trusted(false) {
+        return None;
+    }
+    unsafe {
+        let system = CFObject::from_create(AXUIElementCreateSystemWide())?;
+        let focused =
+            CFObject::from_create(ax_copy_attribute(system.as_ptr(), "AXFocusedUIElement")?)?;
+        let text = CFObject::from_create(ax_copy_attribute(focused.as_ptr(), "AXSelectedText")?)?;
+        let text = text.as_ptr() as id;
+        let is_string: BOOL = msg_send![text, isKindOfClass: class!(NSString)];
+        let res = if is_string == YES {
+            nsstring_to_string(text)
+        } else {
+            None
+        };
+        res.filter(|s| !s.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GammaTable {
+    pub red: Vec<f32>,
+    pub green: Vec<f32>,
+    pub blue: Vec<f32>,
+}
+
+/// The current gamma table of the display.
+pub fn display_gamma(id: u32) -> ResultType<GammaTable> {
+    unsafe {
+        let capacity = CGDisplayGammaTableCapacity(id);
+        if capacity == 0 {
+            bail!("Display {} has no gamma table", id);
+        }
+        let mut table = GammaTable {
+            red: vec![0.; capacity as _],
+            green: vec![0.; capacity as _],
+            blue: vec![0.; capacity as _],
+        };
+        let mut count = 0;
+        let err = CGGetDisplayTransferByTable(
+            id,
+            capacity,
+            table.red.as_mut_ptr(),
+            table.green.as_mut_ptr(),
+            table.blue.as_mut_ptr(),
+            &mut count,
+        );
+        if err != CGError::Success {
+            bail!("CGGetDisplayTransferByTable failed: {:?}", err);
+        }
+        let count = count.min(capacity) as usize;
+        table.red.truncate(count);
+        table.green.truncate(count);
+        table.blue.truncate(count);
+        Ok(table)
+    }
+}
+
+fn set_display_gamma(id: u32, g: &GammaTable) -> ResultType<()> {
+    let len = g.red.len().min(g.green.len()).min(g.blue.len());
+    let err = unsafe {
+        CGSetDisplayTransferByTable(
+            id,
+            len as _,
+            g.red.as_ptr(),
+            g.green.as_ptr(),
+            g.blue.as_ptr(),
+        )
+    };
+    if err != CGError::Success {
+        bail!("CGSetDisplayTransferByTable failed: {:?}", err);
+    }
+    Ok(())
+}
+
+/// Restore the gamma tables from ColorSync, e.g. an app crashed and left the captured frames washed out.
+/// Note that ColorSync settings are restored for all displays, not only `id`.
+pub fn reset_display_gamma(id: u32) -> ResultType<()> {
+    if !scrap::quartz::Display::online()
+        .map(|v| v.iter().any(|d| d.id() == id))
+        .unwrap_or(false)
+    {
+        bail!("Display {} is not online", id);
+    }
+    log::info!("Reset gamma of display {}", id);
+    unsafe { CGDisplayRestoreColorSyncSettings() };
+    Ok(())
+}
+
+/// The time since the last input event of the HID system.
+/// The events injected at the HID tap, e.g. by enigo, count as activity too.
+pub fn get_system_idle_time() -> Duration {
+    let secs = unsafe {
+        CGEventSourceSecondsSinceLastEventType(
+            CG_EVENT_SOURCE_STATE_HID_SYSTEM,
+            CG_ANY_INPUT_EVENT_TYPE,
+        )
+    };
+    if secs.is_finite() && secs > 0. {
+        Duration::from_secs_f64(secs)
+    } else {
+        Duration::ZERO
+    }
+}
+
+/// The time since the last input event of the session.
+/// The events injected by remote control count as activity, as in `get_system_idle_time()`,
+/// so it is zero right after remote input. The idle disconnect measures the local user only.
+pub fn get_idle_time() -> ResultType<Duration> {
+    let secs = unsafe {
+        CGEventSourceSecondsSinceLastEventType(
+            CG_EVENT_SOURCE_STATE_COMBINED_SESSION,
+            CG_ANY_INPUT_EVENT_TYPE,
+        )
+    };
+    if !secs.is_finite() || secs < 0. {
+        bail!("Invalid idle time {}", secs);
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
+/// Register the callback which ends the sessions when the host has been idle too long.
+pub fn on_idle_disconnect(callback: impl Fn() + Send + Sync + 'static) {
+    *IDLE_DISCONNECT_CALLBACK.lock().unwrap() = Some(Arc::new(callback));
+}
+
+// The idle time of the local user, the injected events are skipped by the monitor tap.
+// Falls back to `get_system_idle_time()` if the tap is not running, e.g. no permission.
+fn local_idle_time(monitor_running: &AtomicBool) -> Duration {
+    if monitor_running.load(Ordering::SeqCst) {
+        let secs = unsafe { MacLocalInputIdleSeconds() };
+        if secs.is_finite() && secs >= 0. {
+            return Duration::from_secs_f64(secs);
+        }
+    }
+    get_system_idle_time()
+}
+
+/// Call the `on_idle_disconnect` callback once the host has been idle for `timeout`.
+/// It is called again only after some new activity, `None` disables the monitor.
+pub fn set_idle_disconnect(timeout: Option<Duration>) {
+    let mut lock = IDLE_DISCONNECT_EXIT.lock().unwrap();
+    if let Some(exit) = lock.take() {
+        exit.store(true, Ordering::SeqCst);
+    }
+    let Some(timeout) = timeout else {
+        return;
+    };
+    log::info!("Idle disconnect after {:?}", timeout);
+    let exit = Arc::new(AtomicBool::new(false));
+    *lock = Some(exit.clone());
+    let monitor_running = Arc::new(AtomicBool::new(true));
+    let exit_cloned = exit.clone();
+    let monitor_running_cloned = monitor_running.clone();
+    std::thread::spawn(move || {
+        let ok = unsafe {
+            MacRunLocalInputMonitor(
+                enigo::ENIGO_INPUT_EXTRA_VALUE,
+                exit_cloned.as_ptr() as *const bool,
+            )
+        };
+        if ok == NO {
+            log::warn!("Failed to monitor the local input, injected input counts as activity");
+            monitor_running_cloned.store(false, Ordering::SeqCst);
+        }
+    });
+    std::thread::spawn(move || {
+        let mut fired = false;
+        while !exit.load(Ordering::SeqCst) {
+            let idle = local_idle_time(&monitor_running);
+            if idle < timeout {
+                fired = false;
+            } else if !fired {
+                fired = true;
+                log::info!("Host idle for {:?}, disconnect", idle);
+                // Cloned out, the callback may register another one.
+                let callback = IDLE_DISCONNECT_CALLBACK.lock().unwrap().clone();
+                if let Some(callback) = callback {
+                    callback();
+                }
+            }
+            std::thread::sleep(IDLE_CHECK_INTERVAL);
+        }
+    });
+}
+
+/// The number of displays which can be drawn to.
+/// It is 0 for a MacBook in clamshell mode without an external monitor.
+pub fn active_displays_count() -> u32 {
+    let mut count = 0;
+    unsafe {
+        if CGGetActiveDisplayList(0, std::ptr::null_mut(), &mut count) != CGError::Success {
+            return 0;
+        }
+    }
+    count
+}
+
+type CaptureAudioCallback =
+    extern "C" fn(data: *const f32, frames: u32, channels: u32, sample_rate: u32);
+
+const CAPTURE_AUDIO_OK: i32 = 0;
+const CAPTURE_AUDIO_UNSUPPORTED: i32 = 1;
+
+extern "C" fn on_capture_audio(data: *const f32, frames: u32, channels: u32, sample_rate: u32) {
+    if data.is_null() || frames == 0 || channels == 0 {
+        return;
+    }
+    let data = unsafe { std::slice::from_raw_parts(data, (frames * channels) as _) };
+    if let Some(sink) = CAPTURE_AUDIO_SINK.lock().unwrap().as_ref() {
+        sink(data, sample_rate, channels as _);
+    }
+}
+
+/// The audio input name selecting the system audio of `set_capture_audio()`, like the loopback on Windows.
+pub const SYSTEM_SOUND_INPUT: &str = "System Sound";
+
+#[inline]
+pub fn can_capture_system_audio() -> bool {
+    major_version() >= 13
+}
+
+/// Set the receiver of the captured system audio, interleaved f32 PCM with its sample rate and channels.
+/// `None` releases the receiver, e.g. after the capture is stopped.
+pub fn set_capture_audio_sink(sink: Option<Box<dyn Fn(&[f32], u32, u16) + Send>>) {
+    *CAPTURE_AUDIO_SINK.lock().unwrap() = sink;
+}
+
+/// Capture the system audio with ScreenCaptureKit, macOS >= 13.
+/// The frames are delivered to the sink set by `set_capture_audio_sink()`.
+/// It waits for the stream to start, please do not call it on the main thread.
+pub fn set_capture_audio(enabled: bool) -> ResultType<()> {
+    if enabled && major_version() < 13 {
+        bail!("Capturing system audio is unsupported on this macOS version, macOS 13 or later is required");
+    }
+    match unsafe { MacSetCaptureAudio(if enabled { YES } else { NO }, on_capture_audio) } {
+        CAPTURE_AUDIO_OK => Ok(()),
+        CAPTURE_AUDIO_UNSUPPORTED => {
+            bail!("Capturing system audio is unsupported on this macOS version")
+        }
+        _ => bail!("Failed to start system audio capture"),
+    }
+}
+
+/// The path of our app bundle, e.g. /Applications/RustDesk.app, or on a mounted DMG.
+pub fn bundle_path() -> PathBuf {
+    unsafe {
+        let bundle: id = msg_send![class!(NSBundle), mainBundle];
+        if bundle != nil {
+            let path: id = msg_send![bundle, bundlePath];
+            if let Some(path) = nsstring_to_string(path) {
+                if path.ends_with(".app") {
+                    return PathBuf::from(path);
+                }
+            }
+        }
+    }
+    // Not launched from a bundle, e.g. `cargo run`, try the ancestors of the executable.
+    if let Ok(exe) = std::env::current_exe() {
+        if let Some(p) = exe
+            .ancestors()
+            .find(|p| p.extension().map(|e| e == "app").unwrap_or(false))
+        {
+            return p.to_path_buf();
+        }
+    }
+    PathBuf::from(format!("/Applications/{}.app", crate::get_app_name()))
+}
+
+/// The path of a file in the Resources directory of our app bundle.
+pub fn resource_path(name: &str) -> PathBuf {
+    bundle_path().join("Contents").join("Resources").join(name)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GpuInfo {
+    pub name: String,
+    // The integrated GPU of a dual-GPU Mac.
+    pub is_low_power: bool,
+}
+
+pub fn has_gpu_mux() -> bool {
+    unsafe { MacHasGpuMux() == YES }
+}
+
+/// The GPU driving the main display.
+pub fn current_gpu() -> Option<GpuInfo> {
+    let mut buf = [0 as c_char; 256];
+    let mut low_power = NO;
+    unsafe {
+        if NO == MacGetCurrentGpu(buf.as_mut_ptr(), buf.len() as _, &mut low_power) {
+            return None;
+        }
+        Some(GpuInfo {
+            name: CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned(),
+            is_low_power: low_power == YES,
+        })
+    }
+}
+
+/// Call `callback` when the system switches between the integrated and discrete GPU,
+/// so that the capture stream can be re-established. `None` if the Mac has no GPU mux.
+pub fn watch_gpu_switch(callback: impl Fn(GpuInfo) + Send + 'static) -> Option<WatchHandle> {
+    if !has_gpu_mux() {
+        return None;
+    }
+    // A GPU switch moves the displays to the other GPU, which is reported as a reconfiguration.
+    let last = Mutex::new(current_gpu());
+    Some(on_display_reconfigure(move || {
+        let cur = current_gpu();
+        let mut last = last.lock().unwrap();
+        if cur != *last {
+            log::info!("GPU switched from {:?} to {:?}", *last, cur);
+            if let Some(gpu) = cur.clone() {
+                callback(gpu);
+            }
+            *last = cur;
+        }
+    }))
+}
+
+/// Pin the discrete GPU during capture, or release it.
+pub fn request_discrete_gpu(enable: bool) -> ResultType<()> {
+    if !has_gpu_mux() {
+        bail!("No GPU mux");
+    }
+    if unsafe { MacRequestDiscreteGpu(if enable { YES } else { NO }) } == NO {
+        bail!("Failed to activate the discrete GPU");
+    }
+    Ok(())
+}
+
+/// The raw EDID of the display, for diagnosing the modes offered by `resolutions()`.
+pub fn display_edid(id: u32) -> Option<Vec<u8>> {
+    // 128 bytes per block, EDID usually has 1 or 2 blocks.
+    let mut buf = vec![0u8; 512];
+    let mut size = unsafe { MacGetDisplayEdid(id, buf.as_mut_ptr(), buf.len() as _) };
+    if size as usize > buf.len() {
+        buf.resize(size as _, 0);
+        size = unsafe { MacGetDisplayEdid(id, buf.as_mut_ptr(), buf.len() as _) };
+    }
+    if size == 0 || size as usize > buf.len() {
+        return None;
+    }
+    buf.truncate(size as _);
+    Some(buf)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScreenshotFormat {
+    Png,
+    // quality: 1 - 100
+    Jpeg(u8),
+}
+
+/// Capture a single frame of the display with the capture stream of sessions.
+/// Returns the width, height and the tightly packed BGRA pixels.
+pub fn take_screenshot(display: u32) -> ResultType<(usize, usize, Vec<u8>)> {
+    if !is_can_screen_recording(false) {
+        bail!("No permission to record the screen");
+    }
+    let Some(d) = scrap::Display::all()?
+        .into_iter()
+        .find(|d| d.name() == display.to_string())
+    else {
+        bail!("Display {} is not online", display);
+    };
+    let mut capturer = scrap::Capturer::new_with_format(d, scrap::PixelFormat::Argb8888)?;
+    let start = Instant::now();
+    loop {
+        match capturer.frame(Duration::from_millis(100)) {
+            Ok(scrap::Frame::PixelBuffer(f)) => {
+                let (width, height) = (f.width(), f.height());
+                let stride = f.stride().first().copied().unwrap_or(width * 4);
+                let mut bgra = Vec::with_capacity(width * height * 4);
+                for row in f.data().chunks(stride).take(height) {
+                    bgra.extend_from_slice(&row[..width * 4]);
+                }
+                return Ok((width, height, bgra));
+            }
+            Ok(scrap::Frame::Texture(_)) => bail!("Unexpected texture frame"),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(e) => bail!("Failed to capture display {}: {}", display, e),
+        }
+        if start.elapsed() >= SCREENSHOT_TIMEOUT {
+            bail!("Timed out capturing display {}", display);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Capture one frame of the display and write it to `path` atomically, e.g. for audit snapshots.
+/// Nothing is written if `cancel` is set before the file is in place.
+pub fn capture_screenshot_to_file(
+    display: u32,
+    path: &std::path::Path,
+    format: ScreenshotFormat,
+    cancel: &AtomicBool,
+) -> ResultType<()> {
+    let (width, height, bgra) = take_screenshot(display)?;
+    let rgba: Vec<u8> = bgra
+        .chunks_exact(4)
+        .flat_map(|p| [p[2], p[1], p[0], p[3]])
+        .collect();
+    let data = unsafe {
+        let pool = NSAutoreleasePool::new(nil);
+        let res = encode_screenshot(width, height, &rgba, format);
+        pool.drain();
+        res?
+    };
+    if data.len() > MAX_SCREENSHOT_FILE_SIZE {
+        bail!(
+            "Screenshot is too large, {} bytes > {} bytes",
+            data.len(),
+            MAX_SCREENSHOT_FILE_SIZE
+        );
+    }
+    if cancel.load(Ordering::SeqCst) {
+        bail!("Screenshot cancelled");
+    }
+    // Unique in the same directory, the rename must not cross file systems or collide with other snapshots.
+    static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+    let tmp = path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    let res = std::fs::write(&tmp, &data).and_then(|_| {
+        if cancel.load(Ordering::SeqCst) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "Screenshot cancelled",
+            ));
+        }
+        std::fs::rename(&tmp, path)
+    });
+    if let Err(e) = res {
+        std::fs::remove_file(&tmp).ok();
+        if e.raw_os_error() == Some(scrap::libc::ENOSPC) {
+            bail!("No space left on device to write {:?}", path);
+        }
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            bail!("No permission to write {:?}", path);
+        }
+        bail!("Failed to write {:?}: {}", path, e);
+    }
+    Ok(())
+}
+
+unsafe fn encode_screenshot(
+    width: usize,
+    height: usize,
+    rgba: &[u8],
+    format: ScreenshotFormat,
+) -> ResultType<Vec<u8>> {
+    let rep = rgba_bitmap_rep(width, height, rgba)?;
+    let data: id = match format {
+        ScreenshotFormat::Png => {
+            let props: id = msg_send![class!(NSDictionary), dictionary];
+            msg_send![rep, representationUsingType: NS_BITMAP_IMAGE_FILE_TYPE_PNG properties: props]
+        }
+        ScreenshotFormat::Jpeg(quality) => {
+            let factor = quality.clamp(1, 100) as f64 / 100.;
+            let factor: id = msg_send![class!(NSNumber), numberWithDouble: factor];
+            let key = NSString::alloc(nil)
+                .init_str("NSImageCompressionFactor")
+                .autorelease();
+            let props = NSDictionary::dictionaryWithObject_forKey_(nil, factor, key);
+            msg_send![rep, representationUsingType: NS_BITMAP_IMAGE_FILE_TYPE_JPEG properties: props]
+        }
+    };
+    if data == nil {
+        bail!("Failed to encode screenshot");
+    }
+    Ok(nsdata_to_vec(data))
+}
+
+pub fn caps_lock_on() -> bool {
+    unsafe {
+        CGEventSourceFlagsState(CG_EVENT_SOURCE_STATE_HID_SYSTEM) & CG_EVENT_FLAG_MASK_ALPHA_SHIFT
+            != 0
+    }
+}
+
+/// Most Mac keyboards have no Num Lock, false is returned if the state is unavailable.
+pub fn num_lock_on() -> bool {
+    let mut state = NO;
+    unsafe { MacGetModifierLockState(IOHID_NUM_LOCK_STATE, &mut state) == YES && state == YES }
+}
+
+/// Stops the lock keys watcher on drop.
+pub struct LockKeysWatcher(Arc<AtomicBool>);
+
+impl Drop for LockKeysWatcher {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Call `callback(caps_lock, num_lock)` when either state changes, e.g. to sync the client's indicators.
+pub fn watch_lock_keys(callback: impl Fn(bool, bool) + Send + 'static) -> LockKeysWatcher {
+    let exit = Arc::new(AtomicBool::new(false));
+    let exit_cloned = exit.clone();
+    std::thread::spawn(move || {
+        let mut last = (caps_lock_on(), num_lock_on());
+        while !exit_cloned.load(Ordering::SeqCst) {
+            std::thread::sleep(LOCK_KEYS_CHECK_INTERVAL);
+            let cur = (caps_lock_on(), num_lock_on());
+            if cur != last {
+                last = cur;
+                callback(cur.0, cur.1);
+            }
+        }
+    });
+    LockKeysWatcher(exit)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VolumeInfo {
+    pub mount_point: String,
+    pub total: u64,
+    // Available to unprivileged users.
+    pub free: u64,
+    pub is_root_volume: bool,
+}
+
+/// Mounted volumes with storage, pseudo file systems and hidden system volumes are filtered out.
+pub fn disk_usage() -> Vec<VolumeInfo> {
+    use scrap::libc::{getmntinfo, statfs, MNT_DONTBROWSE, MNT_NOWAIT};
+    const PSEUDO_FS: [&str; 4] = ["devfs", "autofs", "nullfs", "fdesc"];
+    let mut v = vec![];
+    unsafe {
+        let mut mounts: *mut statfs = std::ptr::null_mut();
+        let n = getmntinfo(&mut mounts, MNT_NOWAIT);
+        if n <= 0 || mounts.is_null() {
+            log::error!("getmntinfo failed: {}", std::io::Error::last_os_error());
+            return v;
+        }
+        for m in std::slice::from_raw_parts(mounts, n as _) {
+            let fs_type = CStr::from_ptr(m.f_fstypename.as_ptr()).to_string_lossy();
+            if PSEUDO_FS.contains(&fs_type.as_ref())
+                || m.f_flags & MNT_DONTBROWSE as u32 != 0
+                || m.f_blocks == 0
+            {
+                continue;
+            }
+            let mount_point = CStr::from_ptr(m.f_mntonname.as_ptr())
+                .to_string_lossy()
+                .into_owned();
+            v.push(VolumeInfo {
+                is_root_volume: mount_point == "/",
+                mount_point,
+                total: m.f_blocks * m.f_bsize as u64,
+                free: m.f_bavail * m.f_bsize as u64,
+            });
+        }
+        // The buffer returned by getmntinfo is owned by libc, do not free it.
+    }
+    v
+}
+
+// mDNS group, sending to it is what triggers the Local Network permission.
+const MDNS_PROBE_ADDR: (Ipv4Addr, u16) = (Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+/// Best-effort, there is no public API to query the Local Network permission.
+/// A multicast probe fails with EHOSTUNREACH when the permission is denied.
+/// None if the state cannot be determined, e.g. no LAN interface is up.
+/// The result is cached like the screen recording permission, see `set_permission_cache_ttl()`.
+pub fn is_local_network_permitted() -> Option<bool> {
+    if major_version() < 15 {
+        return Some(true);
+    }
+    let ttl = *PERMISSION_CACHE_TTL.lock().unwrap();
+    if let Some((t, res)) = *LOCAL_NETWORK_CACHE.lock().unwrap() {
+        if t.elapsed() < ttl {
+            return Some(res);
+        }
+    }
+    probe_local_network()
+}
+
+fn probe_local_network() -> Option<bool> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    // Empty mDNS query header, ignored by responders.
+    let res = match socket.send_to(&[0u8; 12], MDNS_PROBE_ADDR) {
+        Ok(_) => true,
+        Err(e) if e.raw_os_error() == Some(scrap::libc::EHOSTUNREACH) => false,
+        Err(e) => {
+            log::debug!("Local network probe failed: {}", e);
+            return None;
+        }
+    };
+    LOCAL_NETWORK_CACHE
+        .lock()
+        .unwrap()
+        .replace((Instant::now(), res));
+    Some(res)
+}
+
+/// Trigger the Local Network permission prompt, the system shows it only once per app.
+pub fn request_local_network_access() -> Option<bool> {
+    if major_version() < 15 {
+        return Some(true);
+    }
+    let permitted = probe_local_network();
+    if permitted == Some(false) {
+        log::warn!("Local network access is denied, enable it in System Settings > Privacy & Security > Local Network");
+    }
+    permitted
+}
+
+/// Snapshot of the host state a session may change, restored on drop.
+/// Mouse acceleration, mirroring and arrangement are not changed by sessions on macOS yet,
+/// so they are not part of the snapshot.
+/// The screen is unblanked only if it was not blanked before, e.g. by another session.
+/// Privacy mode is always disabled, it blocks the local input which is unblocked on drop.
+pub struct SessionStateGuard {
+    _resolutions: Vec<ResolutionRestore>,
+    gammas: Vec<(u32, GammaTable)>,
+    screen_blanked: bool,
+}
+
+impl SessionStateGuard {
+    pub fn new() -> Self {
+        let mut resolutions = vec![];
+        let mut gammas = vec![];
+        for display in scrap::quartz::Display::online().unwrap_or_default() {
+            let id = display.id();
+            if let Some(r) = ResolutionRestore::new(&id.to_string()) {
+                resolutions.push(r);
+            }
+            if let Ok(g) = display_gamma(id) {
+                gammas.push((id, g));
+            }
+        }
+        Self {
+            _resolutions: resolutions,
+            gammas,
+            screen_blanked: SCREEN_BLANKED.load(Ordering::SeqCst),
+        }
+    }
+}
+
+impl Drop for SessionStateGuard {
+    fn drop(&mut self) {
+        // The resolutions are restored by the ResolutionRestore fields.
+        if !self.screen_blanked {
+            toggle_blank_screen(false);
+        }
+        // Restore the backlights before the local user gets the input back.
+        if let Err(e) = privacy_mode(false) {
+            log::error!("Failed to disable privacy mode: {}", e);
+        }
+        let _ = block_input(false);
+        if mouse_mode() == MouseMode::Relative {
+            if let Err(e) = set_mouse_mode(MouseMode::Absolute) {
+                log::error!("Failed to revert mouse mode: {}", e);
+            }
+        }
+        for (id, g) in &self.gammas {
+            if display_gamma(*id)
+                .map(|cur| cur.red == g.red && cur.green == g.green && cur.blue == g.blue)
+                .unwrap_or(true)
+            {
+                continue;
+            }
+            match set_display_gamma(*id, g) {
+                Ok(_) => log::info!("Reverted gamma of display {}", id),
+                Err(e) => log::error!("Failed to revert gamma of display {}: {}", id, e),
+            }
+        }
+        if ORIGINAL_DOCK_AUTOHIDE.lock().unwrap().is_some() {
+            match restore_system_dock_autohide() {
+                Ok(_) => log::info!("Reverted Dock autohide"),
+                Err(e) => log::error!("Failed to revert Dock autohide: {}", e),
+            }
+        }
+    }
+}
+
+// System defaults of macOS, blue accent.
+const DEFAULT_ACCENT_COLOR: (u8, u8, u8) = (0, 122, 255);
+const DEFAULT_HIGHLIGHT_COLOR: (u8, u8, u8) = (179, 215, 255);
+
+fn system_color(which: i32, default: (u8, u8, u8)) -> (u8, u8, u8) {
+    let mut rgb = [0u8; 3];
+    if unsafe { MacGetSystemColor(which, rgb.as_mut_ptr()) } == YES {
+        (rgb[0], rgb[1], rgb[2])
+    } else {
+        default
+    }
+}
+
+/// The accent color of the host in sRGB, resolved against the current appearance.
+pub fn get_accent_color() -> (u8, u8, u8) {
+    system_color(0, DEFAULT_ACCENT_COLOR)
+}
+
+/// The text selection color of the host in sRGB, resolved against the current appearance.
+pub fn get_highlight_color() -> (u8, u8, u8) {
+    system_color(1, DEFAULT_HIGHLIGHT_COLOR)
+}
+
+/// WindowServer has a graphics session with a display, capture and input can work.
+pub fn windowserver_ready() -> bool {
+    unsafe {
+        if CGMainDisplayID() == 0 {
+            return false;
+        }
+        CFObject::from_create(CGSessionCopyCurrentDictionary() as _).is_some()
+    }
+}
+
+/// A user is logged in, but the screen is locked. Unlike `is_prelogin()`, which is at the login window.
+pub fn is_screen_locked() -> bool {
+    unsafe {
+        let Some(session) = CFObject::from_create(CGSessionCopyCurrentDictionary() as _) else {
+            return false;
+        };
+        let key = NSString::alloc(nil).init_str("CGSSessionScreenIsLocked");
+        // Absent if unlocked.
+        let value: id = msg_send![session.as_ptr() as id, objectForKey: key];
+        let locked = if value.is_null() {
+            false
+        } else {
+            let value: BOOL = msg_send![value, boolValue];
+            value == YES
+        };
+        let () = msg_send![key, release];
+        locked
+    }
+}
+
+/// Block until `windowserver_ready()` or `timeout`, return whether it is ready.
+pub fn wait_for_windowserver(timeout: Duration) -> bool {
+    let start = Instant::now();
+    loop {
+        if windowserver_ready() {
+            return true;
+        }
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        std::thread::sleep(WINDOWSERVER_CHECK_INTERVAL);
+    }
+}
+
+/// The pointer scaling (tracking speed) of the active user, 1.0 if unset or acceleration is disabled (-1).
+pub fn pointer_scaling() -> f64 {
+    read_user_default("-g", "com.apple.mouse.scaling")
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|v| v.is_finite() && *v > 0.)
+        .unwrap_or(1.)
+}
+
+// Built-in panels may report 0, assume 60Hz.
+const DEFAULT_REFRESH_RATE: f64 = 60.;
+
+/// The frame interval of the main display.
+pub fn main_display_refresh_interval() -> Duration {
+    let rate = current_refresh_rate(unsafe { CGMainDisplayID() });
+    let rate = if rate > 0. {
+        rate
+    } else {
+        DEFAULT_REFRESH_RATE
+    };
+    Duration::from_secs_f64(1. / rate)
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct NSEdgeInsets {
+    top: f64,
+    left: f64,
+    bottom: f64,
+    right: f64,
+}
+
+/// The area of the display covered by the notch and the menu bar extras next to it, in pixels.
+/// All zero on displays without a notch or before macOS 12.
+pub fn safe_area_insets(id: u32) -> Insets {
+    let mut insets = Insets::new();
+    unsafe {
+        let screens: id = msg_send![class!(NSScreen), screens];
+        if screens == nil {
+            return insets;
+        }
+        let count: usize = msg_send![screens, count];
+        for i in 0..count {
+            let screen: id = msg_send![screens, objectAtIndex: i];
+            let desc: id = msg_send![screen, deviceDescription];
+            let number: id = msg_send![desc, objectForKey: NSString::alloc(nil).init_str("NSScreenNumber").autorelease()];
+            if number == nil {
+                continue;
+            }
+            let number: u32 = msg_send![number, unsignedIntValue];
+            if number != id {
+                continue;
+            }
+            let responds: BOOL = msg_send![screen, respondsToSelector: sel!(safeAreaInsets)];
+            if responds != YES {
+                break;
+            }
+            let v: NSEdgeInsets = msg_send![screen, safeAreaInsets];
+            let scale = scrap::quartz::Display::online()
+                .ok()
+                .and_then(|v| v.into_iter().find(|d| d.id() == id))
+                .map(|d| d.scale())
+                .unwrap_or(1.);
+            insets.top = (v.top * scale).round() as _;
+            insets.left = (v.left * scale).round() as _;
+            insets.bottom = (v.bottom * scale).round() as _;
+            insets.right = (v.right * scale).round() as _;
+            break;
+        }
+    }
+    insets
+}
+
+/// Crop the top safe area inset, i.e. the notch, from the captured frames of all displays.
+/// Takes effect when the capturers are re-created.
+pub fn set_crop_to_safe_area(crop: bool) {
+    for display in scrap::quartz::Display::online().unwrap_or_default() {
+        let top = if crop {
+            safe_area_insets(display.id()).top.max(0) as usize
+        } else {
+            0
+        };
+        scrap::quartz::set_capture_crop_top(display.id(), top);
+    }
+}
+
+// The observers and the flags set by their `WatchHandle` on drop.
+type Observers<E> = Mutex<Vec<(Arc<AtomicBool>, Arc<dyn Fn(E) + Send + Sync>)>>;
+
+fn add_observer<E>(
+    observers: &Observers<E>,
+    callback: impl Fn(E) + Send + Sync + 'static,
+) -> WatchHandle {
+    let removed = Arc::new(AtomicBool::new(false));
+    let mut lock = observers.lock().unwrap();
+    lock.retain(|(removed, _)| !removed.load(Ordering::SeqCst));
+    lock.push((removed.clone(), Arc::new(callback)));
+    WatchHandle(removed)
+}
+
+fn notify_observers<E: Copy>(observers: &Observers<E>, event: E) {
+    // Called without the lock, so that the callbacks can add or remove observers.
+    let callbacks: Vec<_> = {
+        let mut lock = observers.lock().unwrap();
+        lock.retain(|(removed, _)| !removed.load(Ordering::SeqCst));
+        lock.iter().map(|(_, callback)| callback.clone()).collect()
+    };
+    for callback in callbacks {
+        callback(event);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepWakeEvent {
+    WillSleep,
+    DidWake,
+    WillPowerOff,
+}
+
+extern "C" fn on_sleep_wake(event: i32) {
+    let event = match event {
+        0 => SleepWakeEvent::WillSleep,
+        1 => SleepWakeEvent::DidWake,
+        2 => SleepWakeEvent::WillPowerOff,
+        _ => return,
+    };
+    log::info!("System power event: {:?}", event);
+    notify_observers(&SLEEP_WAKE_OBSERVERS, event);
+}
+
+/// Observe system sleep and wake, e.g. to pause the session and notify the client.
+/// `WillSleep` and `WillPowerOff` are acknowledged after the callbacks return,
+/// so sleep is delayed (at most 30 seconds) for a graceful disconnect. Keep them short.
+/// The observer is removed when the returned handle is dropped.
+pub fn register_sleep_wake_observer(
+    callback: impl Fn(SleepWakeEvent) + Send + Sync + 'static,
+) -> WatchHandle {
+    static START_OBSERVER: std::sync::Once = std::sync::Once::new();
+    START_OBSERVER.call_once(|| {
+        std::thread::spawn(|| {
+            if unsafe { MacRunSleepWakeObserver(on_sleep_wake) } != YES {
+                log::error!("Failed to register for system power notifications");
+            }
+        });
+    });
+    add_observer(&SLEEP_WAKE_OBSERVERS, callback)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    Activated,
+    Deactivated,
+}
+
+extern "C" fn on_session(event: i32) {
+    let event = match event {
+        0 => SessionEvent::Activated,
+        1 => SessionEvent::Deactivated,
+        _ => return,
+    };
+    log::info!("Session event: {:?}", event);
+    notify_observers(&SESSION_OBSERVERS, event);
+}
+
+/// Observe fast user switching, e.g. to pause input injection while our session is in the background.
+/// The observer is removed when the returned handle is dropped.
+pub fn on_session_change(callback: impl Fn(SessionEvent) + Send + Sync + 'static) -> WatchHandle {
+    static START_OBSERVER: std::sync::Once = std::sync::Once::new();
+    START_OBSERVER.call_once(|| {
+        // The notifications are delivered on the run loop of this thread.
+        std::thread::spawn(|| unsafe { MacRunSessionObserver(on_session) });
+    });
+    add_observer(&SESSION_OBSERVERS, callback)
+}
+
+/// The reason of the last wake from the power management log, e.g. "EC.LidOpen" or "HID Activity".
+pub fn last_wake_reason() -> Option<String> {
+    let output = std::process::Command::new("pmset")
+        .args(&["-g", "log"])
+        .output()
+        .ok()?;
+    // 2024-01-01 10:00:00 +0100 Wake  	DarkWake to FullWake from Deep Idle [CDNVA] : due to HID Activity Using AC (Charge:100%) 2 secs
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .rev()
+        .filter(|l| {
+            l.split_whitespace()
+                .nth(3)
+                .map(|e| e == "Wake" || e == "DarkWake")
+                .unwrap_or(false)
+        })
+        .find_map(|l| {
+            let reason = l.split("due to ").nth(1)?;
+            let reason = reason.split(" Using ").next().unwrap_or(reason).trim();
+            (!reason.is_empty()).then(|| reason.to_owned())
+        })
+}
+
+/// The pointer size of Accessibility > Display, 1.0 (normal) to 4.0.
+pub fn cursor_size_scale() -> f32 {
+    let mut cache = CURSOR_SIZE_SCALE.lock().unwrap();
+    if let Some((time, v)) = *cache {
+        if time.elapsed() < CURSOR_SIZE_SCALE_TTL {
+            return v;
+        }
+    }
+    let v = read_user_default("com.apple.universalaccess", "mouseDriverCursorSize")
+        .and_then(|v| v.parse::<f32>().ok())
+        .filter(|v| v.is_finite() && *v >= 1.)
+        .unwrap_or(1.);
+    *cache = Some((Instant::now(), v));
+    v
+}
+
+// Nearest neighbor, cursors are small and mostly flat colors.
+fn scale_cursor(cd: &mut CursorData, factor: f32) {
+    let (w, h) = (cd.width.max(0) as usize, cd.height.max(0) as usize);
+    if factor <= 1. || w == 0 || h == 0 || cd.colors.len() != w * h * 4 {
+        return;
+    }
+    let (nw, nh) = (
+        (w as f32 * factor).round() as usize,
+        (h as f32 * factor).round() as usize,
+    );
+    let mut colors = Vec::with_capacity(nw * nh * 4);
+    for y in 0..nh {
+        let sy = (y * h / nh).min(h - 1);
+        for x in 0..nw {
+            let sx = (x * w / nw).min(w - 1);
+            let i = (sy * w + sx) * 4;
+            colors.extend_from_slice(&cd.colors[i..i + 4]);
+        }
+    }
+    cd.colors = colors.into();
+    cd.width = nw as _;
+    cd.height = nh as _;
+    cd.hotx = (cd.hotx as f32 * factor).round() as _;
+    cd.hoty = (cd.hoty as f32 * factor).round() as _;
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct MacDisplayMode {
+    display: u32,
+    width: u32,
+    height: u32,
+    refresh_rate: f64,
+    current: BOOL,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DisplayMode {
+    pub width: usize,
+    pub height: usize,
+    // 0 for displays without a fixed rate, e.g. some built-in panels.
+    pub refresh_rate: f64,
+}
+
+// The proto has no refresh rate.
+impl From<DisplayMode> for Resolution {
+    fn from(mode: DisplayMode) -> Self {
+        Resolution {
+            width: mode.width as _,
+            height: mode.height as _,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DisplayModes {
+    pub id: u32,
+    pub current: DisplayMode,
+    // Modes with the bit depth of the current one, deduplicated.
+    pub modes: Vec<DisplayMode>,
+}
+
+/// `resolutions` and `current_resolution` of all active displays in one FFI call, e.g. for a monitor picker.
+pub fn all_displays_modes() -> Vec<DisplayModes> {
+    let mut buf = vec![MacDisplayMode::default(); 256];
+    let mut n = unsafe { MacGetAllDisplaysModes(buf.as_mut_ptr(), buf.len() as _) } as usize;
+    if n > buf.len() {
+        buf.resize(n, MacDisplayMode::default());
+        n = unsafe { MacGetAllDisplaysModes(buf.as_mut_ptr(), buf.len() as _) } as usize;
+    }
+    let mut v: Vec<DisplayModes> = vec![];
+    for m in &buf[..n.min(buf.len())] {
+        let mode = DisplayMode {
+            width: m.width as _,
+            height: m.height as _,
+            refresh_rate: m.refresh_rate,
+        };
+        let i = match v.iter().position(|d| d.id == m.display) {
+            Some(i) => i,
+            None => {
+                v.push(DisplayModes {
+                    id: m.display,
+                    ..Default::default()
+                });
+                v.len() - 1
+            }
+        };
+        if m.current == YES {
+            v[i].current = mode;
+        }
+        if !v[i].modes.contains(&mode) {
+            v[i].modes.push(mode);
+        }
+    }
+    v
+}
+
+/// Whether the frontmost app shows a full-screen window, e.g. a video player or a presentation.
+/// Uses the AXFullScreen attribute of its focused window, or without accessibility permission,
+/// whether the current space is a full-screen one.
+pub fn frontmost_app_is_fullscreen() -> bool {
+    if let Some(v) = frontmost_window_fullscreen_ax() {
+        return v;
+    }
+    list_spaces()
+        .iter()
+        .any(|s| s.is_current && s.is_fullscreen)
+}
+
+fn frontmost_window_fullscreen_ax() -> Option<bool> {
+    if !is_process_trusted(false) {
+        return None;
+    }
+    unsafe {
+        let workspace: id = msg_send![class!(NSWorkspace), sharedWorkspace];
+        if workspace == nil {
+            return None;
+        }
+        let app: id = msg_send![workspace, frontmostApplication];
+        if app == nil {
+            return None;
+        }
+        let pid: i32 = msg_send![app, processIdentifier];
+        let element = CFObject::from_create(AXUIElementCreateApplication(pid))?;
+        // No focused window, e.g. Finder with only the desktop.
+        let Some(window) =
+            ax_copy_attribute(element.as_ptr(), "AXFocusedWindow").and_then(CFObject::from_create)
+        else {
+            return Some(false);
+        };
+        let value = CFObject::from_create(ax_copy_attribute(window.as_ptr(), "AXFullScreen")?)?;
+        let value = value.as_ptr() as id;
+        let is_number: BOOL = msg_send![value, isKindOfClass: class!(NSNumber)];
+        if is_number == YES {
+            let b: BOOL = msg_send![value, boolValue];
+            Some(b == YES)
+        } else {
+            None
+        }
+    }
+}
+
+// Virtual interfaces with Ethernet addresses, e.g. AirDrop (awdl), low latency WLAN (llw) and Thunderbolt bridges.
+const VIRTUAL_INTERFACE_PREFIXES: [&str; 5] = ["awdl", "llw", "bridge", "anpi", "ap"];
+
+/// Hardware addresses of active physical interfaces, sorted by name so en0 comes first.
+pub fn mac_addresses() -> Vec<(String, [u8; 6])> {
+    use scrap::libc::{
+        freeifaddrs, getifaddrs, ifaddrs, sockaddr_dl, AF_LINK, IFF_LOOPBACK, IFF_RUNNING, IFF_UP,
+    };
+    // net/if_types.h
+    const IFT_ETHER: u8 = 0x6;
+    let mut v = vec![];
+    unsafe {
+        let mut addrs: *mut ifaddrs = std::ptr::null_mut();
+        if getifaddrs(&mut addrs) != 0 {
+            log::error!("getifaddrs failed: {}", std::io::Error::last_os_error());
+            return v;
+        }
+        let mut cur = addrs;
+        while !cur.is_null() {
+            let ifa = &*cur;
+            cur = ifa.ifa_next;
+            if ifa.ifa_name.is_null()
+                || ifa.ifa_addr.is_null()
+                || (*ifa.ifa_addr).sa_family as i32 != AF_LINK
+                || ifa.ifa_flags & (IFF_UP | IFF_RUNNING) as u32 != (IFF_UP | IFF_RUNNING) as u32
+                || ifa.ifa_flags & IFF_LOOPBACK as u32 != 0
+            {
+                continue;
+            }
+            let name = CStr::from_ptr(ifa.ifa_name).to_string_lossy().into_owned();
+            if VIRTUAL_INTERFACE_PREFIXES
+                .iter()
+                .any(|p| name.starts_with(p))
+            {
+                continue;
+            }
+            let dl = &*(ifa.ifa_addr as *const sockaddr_dl);
+            if dl.sdl_type != IFT_ETHER || dl.sdl_alen != 6 {
+                continue;
+            }
+            // The address follows the name in sdl_data, which may extend beyond the declared array.
+            let data = (dl.sdl_data.as_ptr() as *const u8).add(dl.sdl_nlen as usize);
+            let mut mac = [0u8; 6];
+            std::ptr::copy_nonoverlapping(data, mac.as_mut_ptr(), 6);
+            if mac != [0u8; 6] {
+                v.push((name, mac));
+            }
+        }
+        freeifaddrs(addrs);
+    }
+    v.sort_by(|a, b| a.0.cmp(&b.0));
+    v.dedup_by(|a, b| a.0 == b.0);
+    v
+}
+
+// Asks apps to quit, they may block it, e.g. with unsaved documents.
+fn system_events_power_action(action: &str) -> ResultType<()> {
+    let output = active_user_command("osascript")
+        .arg("-e")
+        .arg(format!(r#"tell application "System Events" to {}"#, action))
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "Failed to {}: {}",
+            action,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+// `shutdown` requires root, ask for the administrator password if we are not.
+fn force_shutdown(flag: &str) -> ResultType<()> {
+    let status = if is_root() {
+        std::process::Command::new("/sbin/shutdown")
+            .args(&[flag, "now"])
+            .status()?
+    } else {
+        let prompt = get_privileges_prompt(format!(
+            "{} wants to restart or shut down this Mac",
+            crate::get_app_name()
+        ));
+        std::process::Command::new("osascript")
+            .arg("-e")
+            .arg(format!(
+                r#"do shell script "/sbin/shutdown {} now" with prompt "{}" with administrator privileges"#,
+                flag,
+                escape_applescript_string(&prompt)
+            ))
+            .status()?
+    };
+    if !status.success() {
+        bail!("Failed to run shutdown {}: {}", flag, status);
+    }
+    Ok(())
+}
+
+/// Shut down the Mac, `force` does not wait for apps which may block it.
+pub fn system_shutdown(force: bool) -> ResultType<()> {
+    log::info!("System shutdown, force: {}", force);
+    if force {
+        force_shutdown("-h")
+    } else {
+        system_events_power_action("shut down")
+    }
+}
+
+/// Restart the Mac, `force` does not wait for apps which may block it.
+pub fn system_restart(force: bool) -> ResultType<()> {
+    log::info!("System restart, force: {}", force);
+    if force {
+        force_shutdown("-r")
+    } else {
+        system_events_power_action("restart")
+    }
+}
+
+/// Log out the active user, `force` terminates the user's GUI session without asking apps.
+pub fn system_logout(force: bool) -> ResultType<()> {
+    log::info!("System logout, force: {}", force);
+    if !force {
+        return system_events_power_action("log out");
+    }
+    let uid = get_active_userid();
+    if uid.is_empty() || uid == "0" {
+        bail!("No user is logged in");
+    }
+    let status = std::process::Command::new("launchctl")
+        .args(&["bootout", &format!("gui/{}", uid)])
+        .status()?;
+    if !status.success() {
+        bail!("Failed to log out user {}: {}", uid, status);
+    }
+    Ok(())
+}
+
+/// Restores the mode of a display on drop, e.g. held for the lifetime of a session.
+/// Overlapping guards share the mode recorded by the first one, which is restored when the last one drops.
+pub struct ResolutionRestore {
+    name: String,
+}
+
+impl ResolutionRestore {
+    pub fn new(name: &str) -> Option<Self> {
+        let mut modes = ORIGINAL_MODES.lock().unwrap();
+        match modes.get_mut(name) {
+            Some((_, count)) => *count += 1,
+            None => {
+                let r = current_resolution(name).ok()?;
+                let mode = DisplayMode {
+                    width: r.width as _,
+                    height: r.height as _,
+                    refresh_rate: resolve_display_id(name)
+                        .map(current_refresh_rate)
+                        .unwrap_or_default(),
+                };
+                modes.insert(name.to_owned(), (mode, 1));
+            }
+        }
+        Some(Self {
+            name: name.to_owned(),
+        })
+    }
+}
+
+impl Drop for ResolutionRestore {
+    fn drop(&mut self) {
+        let mode = {
+            let mut modes = ORIGINAL_MODES.lock().unwrap();
+            let Some((mode, count)) = modes.get_mut(&self.name) else {
+                return;
+            };
+            *count -= 1;
+            if *count > 0 {
+                return;
+            }
+            let mode = *mode;
+            modes.remove(&self.name);
+            mode
+        };
+        // A debounced request must not override the restored mode.
+        PENDING_RESOLUTIONS.lock().unwrap().remove(&self.name);
+        let Ok(cur) = current_resolution(&self.name) else {
+            return;
+        };
+        let cur_rate = resolve_display_id(&self.name)
+            .map(current_refresh_rate)
+            .unwrap_or_default();
+        if cur.width as usize == mode.width
+            && cur.height as usize == mode.height
+            && (cur_rate - mode.refresh_rate).abs() < 0.5
+        {
+            return;
+        }
+        match change_resolution_directly_with_rate(
+            &self.name,
+            mode.width,
+            mode.height,
+            mode.refresh_rate,
+        ) {
+            Ok(_) => log::info!(
+                "Restored mode of display {} to {}x{}@{}Hz",
+                self.name,
+                mode.width,
+                mode.height,
+                mode.refresh_rate
+            ),
+            Err(e) => log::error!("Failed to restore mode of display {}: {}", self.name, e),
+        }
+    }
+}
+
+/// Geometry of an active display in the global coordinates of `get_cursor_pos`, in points.
+/// Not `DisplayInfo`, which is the message sent to peers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DisplayGeometry {
+    pub id: u32,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    // Pixels per point, 2 on Retina displays.
+    pub scale: f64,
+    pub is_primary: bool,
+}
+
+pub fn displays() -> Vec<DisplayGeometry> {
+    const MAX_DISPLAYS: u32 = 16;
+    let mut ids = [0u32; MAX_DISPLAYS as usize];
+    let mut count = 0;
+    unsafe {
+        if CGGetActiveDisplayList(MAX_DISPLAYS, ids.as_mut_ptr(), &mut count) != CGError::Success {
+            return vec![];
+        }
+        ids[..count.min(MAX_DISPLAYS) as usize]
+            .iter()
+            .map(|&id| {
+                let bounds = CGDisplayBounds(id);
+                DisplayGeometry {
+                    id,
+                    x: bounds.origin.x,
+                    y: bounds.origin.y,
+                    width: bounds.size.width,
+                    height: bounds.size.height,
+                    scale: BackingScaleFactor(id).max(1.) as _,
+                    is_primary: CGDisplayIsMain(id) != 0,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The display containing the global point, with the point translated to the display's local space.
+pub fn display_at_point(x: f64, y: f64) -> Option<(DisplayGeometry, f64, f64)> {
+    displays()
+        .into_iter()
+        .find(|d| x >= d.x && x < d.x + d.width && y >= d.y && y < d.y + d.height)
+        .map(|d| {
+            let (lx, ly) = (x - d.x, y - d.y);
+            (d, lx, ly)
+        })
+}
+
+/// The UUID of the display, stable across reboots and hot-plugs unlike the display id.
+pub fn display_uuid(id: u32) -> Option<String> {
+    unsafe {
+        let uuid = CFObject::from_create(CGDisplayCreateUUIDFromDisplayID(id))?;
+        let s = CFUUIDCreateString(std::ptr::null(), uuid.as_ptr());
+        if s.is_null() {
+            return None;
+        }
+        Some(CFString::wrap_under_create_rule(s).to_string())
+    }
+}
+
+/// Resolve a display name, a UUID or a display id for backward compatibility, to the current display id.
+pub fn resolve_display_id(name: &str) -> ResultType<u32> {
+    if let Ok(id) = name.parse::<u32>() {
+        return Ok(id);
+    }
+    for display in scrap::quartz::Display::online().unwrap_or_default() {
+        if display_uuid(display.id())
+            .map(|uuid| uuid.eq_ignore_ascii_case(name))
+            .unwrap_or(false)
+        {
+            return Ok(display.id());
+        }
+    }
+    bail!("No display matches '{}'", name)
+}
+
+// The private framework controlling the backlight of Apple displays.
+struct DisplayServicesApi {
+    get_brightness: extern "C" fn(u32, *mut f32) -> i32,
+    set_brightness: extern "C" fn(u32, f32) -> i32,
+}
+
+impl DisplayServicesApi {
+    unsafe fn load() -> Option<Self> {
+        use scrap::libc::{dlopen, dlsym, RTLD_LAZY};
+        let handle = dlopen(
+            b"/System/Library/PrivateFrameworks/DisplayServices.framework/DisplayServices\0"
+                .as_ptr() as _,
+            RTLD_LAZY,
+        );
+        if handle.is_null() {
+            log::warn!("DisplayServices is unavailable");
+            return None;
+        }
+        let get_brightness = dlsym(handle, b"DisplayServicesGetBrightness\0".as_ptr() as _);
+        let set_brightness = dlsym(handle, b"DisplayServicesSetBrightness\0".as_ptr() as _);
+        if get_brightness.is_null() || set_brightness.is_null() {
+            log::warn!("DisplayServices brightness functions are unavailable");
+            return None;
+        }
+        Some(Self {
+            get_brightness: std::mem::transmute(get_brightness),
+            set_brightness: std::mem::transmute(set_brightness),
+        })
+    }
+}
+
+/// Turn off the backlight of all displays and block the local input, while the session continues.
+/// Fails, instead of falling back to blanking, if the brightness of any display can't be controlled,
+/// e.g. most third party external monitors.
+/// Do not combine with `toggle_blank_screen`, enabling fails while the screen is blanked.
+pub fn privacy_mode(enable: bool) -> ResultType<()> {
+    let mut lock = PRIVACY_MODE_BRIGHTNESS.lock().unwrap();
+    if !enable {
+        if let Some(saved) = lock.take() {
+            restore_brightness(&saved);
+            block_input(false);
+            log::info!("Privacy mode disabled");
+        }
+        return Ok(());
+    }
+    if lock.is_some() {
+        return Ok(());
+    }
+    if SCREEN_BLANKED.load(Ordering::SeqCst) {
+        bail!("Privacy mode can't be enabled while the screen is blanked");
+    }
+    let Some(api) = DISPLAY_SERVICES_API.as_ref() else {
+        bail!("PrivacyModeUnsupported: display brightness can't be controlled");
+    };
+    let mut saved = HashMap::new();
+    for display in scrap::quartz::Display::online().unwrap_or_default() {
+        let id = display.id();
+        let mut brightness = 0f32;
+        if (api.get_brightness)(id, &mut brightness) != 0 || (api.set_brightness)(id, 0.) != 0 {
+            restore_brightness(&saved);
+            bail!(
+                "PrivacyModeUnsupported: the brightness of display {} can't be controlled",
+                id
+            );
+        }
+        saved.insert(id, brightness);
+    }
+    let (ok, msg) = block_input(true);
+    if !ok {
+        restore_brightness(&saved);
+        bail!("Failed to block input: {}", msg);
+    }
+    *lock = Some(saved);
+    log::info!("Privacy mode enabled");
+    Ok(())
+}
+
+fn restore_brightness(saved: &HashMap<u32, f32>) {
+    let Some(api) = DISPLAY_SERVICES_API.as_ref() else {
+        return;
+    };
+    for (id, brightness) in saved {
+        if (api.set_brightness)(*id, *brightness) != 0 {
+            log::error!("Failed to restore the brightness of display {}", id);
+        }
+    }
+}
+
+/// Whether the active appearance is Dark, the user's preference if there is no NSApplication, e.g. in the service.
+pub fn is_dark_mode() -> bool {
+    unsafe { MacIsDarkMode() == YES }
+}
+
+type AppearanceCallback = Box<dyn Fn(bool) + Send>;
+
+extern "C" fn on_appearance_change_callback(context: *mut c_void) {
+    let callback = unsafe { &*(context as *const AppearanceCallback) };
+    callback(is_dark_mode());
+}
+
+/// Call `callback(is_dark_mode)` when the user switches between Light and Dark.
+pub fn on_appearance_change(callback: impl Fn(bool) + Send + 'static) -> WatchHandle {
+    let exit = Arc::new(AtomicBool::new(false));
+    let exit_cloned = exit.clone();
+    std::thread::spawn(move || {
+        let Ok(name) = CString::new(APPEARANCE_CHANGED_NOTIFICATION) else {
+            return;
+        };
+        let callback: Box<AppearanceCallback> = Box::new(Box::new(callback));
+        let context = Box::into_raw(callback);
+        unsafe {
+            MacRunDistributedNotificationWatcher(
+                name.as_ptr(),
+                on_appearance_change_callback,
+                context as _,
+                exit_cloned.as_ptr() as *const bool,
+            );
+            // The observer is removed, no callback can use the context any more.
+            drop(Box::from_raw(context));
+        }
+    });
+    WatchHandle(exit)
+}
+
+/// The id of the current keyboard input source, e.g. "com.apple.keylayout.German".
+pub fn current_keyboard_layout() -> Option<String> {
+    let mut buf = [0 as c_char; 256];
+    unsafe {
+        if MacGetCurrentInputSource(buf.as_mut_ptr(), buf.len() as _) == NO {
+            return None;
+        }
+        Some(CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned())
+    }
+}
+
+/// The ids of the enabled keyboard input sources, which `set_keyboard_layout()` accepts.
+pub fn list_keyboard_layouts() -> Vec<String> {
+    let mut buf: Vec<c_char> = vec![0; 4096];
+    unsafe {
+        let required = MacListInputSources(buf.as_mut_ptr(), buf.len() as _) as usize;
+        if required > buf.len() {
+            buf.resize(required, 0);
+            if MacListInputSources(buf.as_mut_ptr(), buf.len() as _) as usize > buf.len() {
+                return vec![];
+            }
+        } else if required == 0 {
+            return vec![];
+        }
+        CStr::from_ptr(buf.as_ptr())
+            .to_string_lossy()
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_owned())
+            .collect()
+    }
+}
+
+pub fn set_keyboard_layout(id: &str) -> ResultType<()> {
+    let c_id = CString::new(id)?;
+    match unsafe { MacSelectInputSource(c_id.as_ptr()) } {
+        0 => Ok(()),
+        1 => bail!("Input source {} is not installed", id),
+        2 => bail!("Input source {} is not enabled", id),
+        _ => bail!("Failed to select input source {}", id),
+    }
+}
+
+extern "C" fn on_display_reconfigure_callback(_display: u32, flags: u32, user_info: *mut c_void) {
+    if flags & CG_DISPLAY_BEGIN_CONFIGURATION_FLAG != 0 {
+        return;
+    }
+    let last = unsafe { &*(user_info as *const Mutex<Option<Instant>>) };
+    last.lock().unwrap().replace(Instant::now());
+}
+
+/// Call `callback` once displays are added, removed or reconfigured, e.g. to re-enumerate `displays()`.
+pub fn on_display_reconfigure(callback: impl Fn() + Send + 'static) -> WatchHandle {
+    let exit = Arc::new(AtomicBool::new(false));
+    let exit_cloned = exit.clone();
+    std::thread::spawn(move || {
+        // The time of the last reconfiguration not notified yet.
+        let last: Box<Mutex<Option<Instant>>> = Default::default();
+        let user_info = &*last as *const _ as *mut c_void;
+        unsafe {
+            if CGDisplayRegisterReconfigurationCallback(on_display_reconfigure_callback, user_info)
+                != CGError::Success
+            {
+                log::error!("Failed to register display reconfiguration callback");
+                return;
+            }
+        }
+        while !exit_cloned.load(Ordering::SeqCst) {
+            // The callback is delivered on the run loop of the registering thread.
+            let res = unsafe {
+                core_foundation::runloop::CFRunLoopRunInMode(
+                    core_foundation::runloop::kCFRunLoopDefaultMode,
+                    0.1,
+                    0,
+                )
+            };
+            if res == core_foundation::runloop::kCFRunLoopRunFinished {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            let settled = {
+                let mut lock = last.lock().unwrap();
+                if lock
+                    .map(|t| t.elapsed() >= DISPLAY_RECONFIGURE_DEBOUNCE)
+                    .unwrap_or(false)
+                {
+                    lock.take();
+                    true
+                } else {
+                    false
+                }
+            };
+            if settled {
+                log::info!("Displays reconfigured");
+                callback();
+            }
+        }
+        unsafe {
+            CGDisplayRemoveReconfigurationCallback(on_display_reconfigure_callback, user_info);
+        }
+    });
+    WatchHandle(exit)
+}
+
+/// A virtual display, removed on drop.
+pub struct VirtualDisplay {
+    id: u32,
+    display: *mut c_void,
+}
+
+// The display object is only released in drop.
+unsafe impl Send for VirtualDisplay {}
+
+impl VirtualDisplay {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Drop for VirtualDisplay {
+    fn drop(&mut self) {
+        log::info!("Remove virtual display {}", self.id);
+        unsafe { MacDestroyVirtualDisplay(self.display) };
+    }
+}
+
+/// Create a virtual display of `width` x `height` pixels, e.g. for a headless Mac without any display.
+/// `scale` is 2 for a HiDPI mode. It uses private SPI, fails if it is not available, e.g. before macOS 11.
+pub fn create_virtual_display(width: u32, height: u32, scale: u32) -> ResultType<VirtualDisplay> {
+    if major_version() < 11 {
+        bail!("Virtual display requires macOS 11 or later");
+    }
+    if width == 0 || height == 0 || scale == 0 || width % scale != 0 || height % scale != 0 {
+        bail!(
+            "Invalid virtual display size {}x{}@{}x",
+            width,
+            height,
+            scale
+        );
+    }
+    let name = CString::new(format!("{} Virtual Display", crate::get_app_name()))?;
+    let mut id = 0;
+    let display = unsafe { MacCreateVirtualDisplay(width, height, scale, name.as_ptr(), &mut id) };
+    if display.is_null() {
+        bail!("Failed to create virtual display, CGVirtualDisplay is not available");
+    }
+    log::info!(
+        "Created virtual display {} of {}x{}@{}x",
+        id,
+        width,
+        height,
+        scale
+    );
+    Ok(VirtualDisplay { id, display })
+}
+
+fn is_main_thread() -> bool {
+    unsafe {
+        let is_main: BOOL = msg_send![class!(NSThread), isMainThread];
+        is_main == YES
+    }
+}
+
+/// Run `f` on the main thread, which AppKit requires, without waiting for it.
+/// Runs `f` immediately if called on the main thread.
+pub fn run_on_main<F: FnOnce() + Send + 'static>(f: F) {
+    if is_main_thread() {
+        f();
+    } else {
+        dispatch::Queue::main().exec_async(f);
+    }
+}
+
+/// Run `f` on the main thread and wait for its result.
+/// Runs `f` immediately if called on the main thread, dispatching synchronously to it would deadlock.
+/// The main thread must be running its run loop, otherwise it blocks forever.
+pub fn run_on_main_sync<T: Send, F: FnOnce() -> T + Send>(f: F) -> T {
+    if is_main_thread() {
+        f()
+    } else {
+        dispatch::Queue::main().exec_sync(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(samples_per_pixel: usize, has_alpha: bool, format: usize) -> BitmapLayout {
+        BitmapLayout {
+            width: 2,
+            height: 1,
+            bytes_per_row: 2 * samples_per_pixel,
+            bits_per_pixel: samples_per_pixel * 8,
+            bits_per_sample: 8,
+            samples_per_pixel,
+            has_alpha,
+            format,
+        }
+    }
+
+    #[test]
+    fn test_bitmap_to_rgba() {
+        let premultiplied = [100, 50, 0, 200, 10, 20, 30, 255];
+        assert_eq!(
+            bitmap_to_rgba(&premultiplied, &layout(4, true, 0)),
+            Some(vec![128, 64, 0, 200, 10, 20, 30, 255])
+        );
+        let alpha_first = [200, 100, 50, 0, 0, 10, 20, 30];
+        assert_eq!(
+            bitmap_to_rgba(
+                &alpha_first,
+                &layout(
+                    4,
+                    true,
+                    NS_BITMAP_FORMAT_ALPHA_FIRST | NS_BITMAP_FORMAT_ALPHA_NONPREMULTIPLIED
+                )
+            ),
+            Some(vec![100, 50, 0, 200, 10, 20, 30, 0])
+        );
+        let gray_alpha = [128, 255, 0, 0];
+        assert_eq!(
+            bitmap_to_rgba(&gray_alpha, &layout(2, true, 0)),
+            Some(vec![128, 128, 128, 255, 0, 0, 0, 0])
+        );
+        let rgb = [1, 2, 3, 4, 5, 6];
+        assert_eq!(
+            bitmap_to_rgba(&rgb, &layout(3, false, 0)),
+            Some(vec![1, 2, 3, 255, 4, 5, 6, 255])
+        );
+        assert_eq!(
+            bitmap_to_rgba(
+                &premultiplied,
+                &layout(4, true, NS_BITMAP_FORMAT_FLOATING_POINT_SAMPLES)
+            ),
+            None
+        );
+        assert_eq!(bitmap_to_rgba(&rgb[..4], &layout(3, false, 0)), None);
+    }
+
+    #[test]
+    fn test_get_double_click_time() {
+        let t = get_double_click_time();
+        assert!(
+            (DOUBLE_CLICK_TIME_MIN..=DOUBLE_CLICK_TIME_MAX).contains(&t),
+            "{}",
+            t
+        );
+        assert_eq!(double_click_time_ms(0.5), 500);
+        assert_eq!(double_click_time_ms(5.), 5000);
+        assert_eq!(double_click_time_ms(0.), DEFAULT_DOUBLE_CLICK_TIME);
+        assert_eq!(double_click_time_ms(-1.), DEFAULT_DOUBLE_CLICK_TIME);
+        assert_eq!(double_click_time_ms(20.), DEFAULT_DOUBLE_CLICK_TIME);
+        assert_eq!(double_click_time_ms(f64::NAN), DEFAULT_DOUBLE_CLICK_TIME);
+    }
+
+    #[test]
+    fn test_rep_pixels_matches_color_path() {
+        unsafe {
+            let _pool = NSAutoreleasePool::new(nil);
+            let cursor: id = msg_send![class!(NSCursor), arrowCursor];
+            let img: id = msg_send![cursor, image];
+            let reps: id = msg_send![img, representations];
+            let rep: id = msg_send![reps, objectAtIndex: 0];
+            let w: NSInteger = msg_send![rep, pixelsWide];
+            let h: NSInteger = msg_send![rep, pixelsHigh];
+            let Some(fast) = rep_pixels(rep, w as _, h as _) else {
+                // Not an 8 bit bitmap on this system, the slow path is used.
+                return;
+            };
+            let slow = rep_pixels_by_color(rep, w as _, h as _);
+            assert_eq!(fast.len(), slow.len());
+            // The slow path truncates the float components.
+            for (a, b) in fast.iter().zip(slow.iter()) {
+                assert!((*a as i32 - *b as i32).abs() <= 1, "{} vs {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_elevate_script_quotes_args() {
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), r#"'it'\''s'"#);
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        let script = elevate_script(
+            "/Applications/Rust Desk.app/Contents/MacOS/RustDesk",
+            &[
+                "--arg",
+                "a \"quoted\" value",
+                "$(id)",
+                "back\\slash",
+                "it's",
+            ],
+            "RustDesk \"wants\"",
+            false,
+        );
+        assert_eq!(
+            script,
+            r#"do shell script "'/Applications/Rust Desk.app/Contents/MacOS/RustDesk' '--arg' 'a \"quoted\" value' '$(id)' 'back\\slash' 'it'\\''s'" with prompt "RustDesk \"wants\"" with administrator privileges"#
+        );
+        assert!(elevate_script("/bin/echo", &[], "", true)
+            .contains(r#"'/bin/echo' > /dev/null 2>&1 &""#));
+    }
+
+    #[test]
+    fn test_wake_lock_state() {
+        for (display, idle, sleep) in [
+            (false, false, false),
+            (true, false, false),
+            (true, true, false),
+            (false, true, true),
+            (true, true, true),
+        ] {
+            let mut lock = WakeLock::new(display, idle, sleep);
+            if !lock.is_active() {
+                assert_eq!(lock.state(), (false, false, false));
+                continue;
+            }
+            assert_eq!(lock.state(), (display, idle, sleep));
+            if lock.set(!display, idle, !sleep).is_ok() {
+                assert_eq!(lock.state(), (!display, idle, !sleep));
+            }
+        }
+    }
+
+    #[test]
+    fn test_display_scale() {
+        assert_eq!(mode_scale(2880, 1440), 2.);
+        assert_eq!(mode_scale(1920, 1920), 1.);
+        assert_eq!(mode_scale(0, 0), 1.);
+        let scale = display_scale(unsafe { CGMainDisplayID() });
+        assert!(scale >= 1. && scale <= 3., "{}", scale);
+    }
+
+    #[test]
+    fn test_cursor_hash_samples() {
+        // A 2x cursor of 32x32 points with the hotspot on the far edge, (32, 32) points is (64, 64) pixels.
+        assert_eq!(cursor_hash_samples(64, 64, 64, 64), [(63, 63), (63, 63)]);
+        assert_eq!(cursor_hash_samples(64, 64, 63, 10), [(63, 10), (63, 11)]);
+        assert_eq!(cursor_hash_samples(32, 32, 0, 0), [(0, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn test_cf_object_released() {
+        use core_foundation::{
+            base::{kCFAllocatorDefault, CFGetRetainCount, CFRetain},
+            data::CFDataCreate,
+        };
+        unsafe {
+            // Not a WindowServer object, so that it runs without a GUI session.
+            let e = CFDataCreate(kCFAllocatorDefault, [0u8; 4].as_ptr(), 4) as *const c_void;
+            assert!(!e.is_null());
+            CFRetain(e);
+            assert_eq!(CFGetRetainCount(e), 2);
+            drop(CFObject::from_create(e));
+            assert_eq!(CFGetRetainCount(e), 1);
+            drop(CFObject::from_create(e));
+        }
+        assert!(CFObject::from_create(std::ptr::null()).is_none());
+    }
+
+    #[test]
+    fn test_daemon_command_program() {
+        let cmd = |exe: &str| {
+            format!(
+                "sleep 3; if pgrep -f {} > /dev/null; then {} --service; fi",
+                shell_quote(&format!("{} --server", exe)),
+                exe
+            )
+        };
+        let exe = "/Applications/RustDesk.app/Contents/MacOS/RustDesk";
+        assert_eq!(daemon_command_program(&cmd(exe)).as_deref(), Some(exe));
+        let exe = "/Volumes/X/Applications/RustDesk.app/Contents/MacOS/RustDesk";
+        assert_eq!(daemon_command_program(&cmd(exe)).as_deref(), Some(exe));
+        let exe = "/Users/a b/It's/RustDesk.app/Contents/MacOS/RustDesk";
+        assert_eq!(
+            daemon_command_program(&cmd(&shell_quote(exe))).as_deref(),
+            Some(exe)
+        );
+        assert_eq!(daemon_command_program("sleep 3"), None);
+    }
+
+    #[test]
+    fn test_parse_osascript_error() {
+        assert_eq!(
+            parse_osascript_error("0:89: execution error: ls: /x: No such file or directory (1)\n"),
+            Some(("ls: /x: No such file or directory".to_owned(), 1))
+        );
+        assert_eq!(
+            parse_osascript_error("0:120: execution error: User canceled. (-128)"),
+            Some(("User canceled.".to_owned(), OSASCRIPT_USER_CANCELED))
+        );
+        assert_eq!(parse_osascript_error("syntax error"), None);
     }
 }
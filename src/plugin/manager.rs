@@ -198,7 +198,7 @@ fn elevate_install(
     if !same_plugin_exists {
         args.push(&plugin_url);
     }
-    crate::platform::elevate(args, "RustDesk wants to install then plugin")
+    crate::platform::elevate_bool(args, "RustDesk wants to install then plugin")
 }
 
 #[inline]
@@ -216,7 +216,7 @@ fn elevate_uninstall(plugin_id: &str) -> ResultType<bool> {
 #[inline]
 #[cfg(target_os = "macos")]
 fn elevate_uninstall(plugin_id: &str) -> ResultType<bool> {
-    crate::platform::elevate(
+    crate::platform::elevate_bool(
         vec!["--plugin-uninstall", plugin_id],
         "RustDesk wants to uninstall the plugin",
     )
@@ -280,8 +280,73 @@ mod cpal_impl {
         Ok((device, format))
     }
 
+    #[cfg(target_os = "macos")]
+    struct SystemAudioStream;
+
+    #[cfg(target_os = "macos")]
+    impl StreamTrait for SystemAudioStream {
+        fn play(&self) -> Result<(), cpal::PlayStreamError> {
+            Ok(())
+        }
+
+        fn pause(&self) -> Result<(), cpal::PauseStreamError> {
+            Ok(())
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    impl Drop for SystemAudioStream {
+        fn drop(&mut self) {
+            allow_err!(crate::platform::macos::set_capture_audio(false));
+            // The sink holds the service and the encoder.
+            crate::platform::macos::set_capture_audio_sink(None);
+        }
+    }
+
+    // ScreenCaptureKit delivers 48000Hz stereo f32.
+    #[cfg(target_os = "macos")]
+    fn play_system_audio(sp: &GenericService) -> ResultType<(Box<dyn StreamTrait>, Arc<Message>)> {
+        const SAMPLE_RATE: u32 = 48000;
+        let sp = sp.clone();
+        let encoder = Mutex::new(Encoder::new(SAMPLE_RATE, Stereo, LowDelay)?);
+        unsafe {
+            AUDIO_ZERO_COUNT = 0;
+        }
+        INPUT_BUFFER.lock().unwrap().clear();
+        crate::platform::macos::set_capture_audio_sink(Some(Box::new(
+            move |data: &[f32], sample_rate: u32, channels: u16| {
+                let frame_len = sample_rate as usize / 100 * channels as usize; // 10 ms
+                let mut lock = INPUT_BUFFER.lock().unwrap();
+                lock.extend(data);
+                while frame_len > 0 && lock.len() >= frame_len {
+                    let frame: Vec<f32> = lock.drain(0..frame_len).collect();
+                    send(
+                        frame,
+                        sample_rate,
+                        SAMPLE_RATE,
+                        channels,
+                        Stereo as _,
+                        &mut encoder.lock().unwrap(),
+                        &sp,
+                    );
+                }
+            },
+        )));
+        // Stops the capture and releases the sink on drop, also if the capture fails to start.
+        let stream = SystemAudioStream;
+        crate::platform::macos::set_capture_audio(true)?;
+        Ok((
+            Box::new(stream),
+            Arc::new(create_format_msg(SAMPLE_RATE, Stereo as _)),
+        ))
+    }
+
     fn play(sp: &GenericService) -> ResultType<(Box<dyn StreamTrait>, Arc<Message>)> {
         use cpal::SampleFormat::*;
+        #[cfg(target_os = "macos")]
+        if super::get_audio_input() == crate::platform::macos::SYSTEM_SOUND_INPUT {
+            return play_system_audio(sp);
+        }
         let (device, config) = get_device()?;
         let sp = sp.clone();
         // Sample rate must be one of 8000, 12000, 16000, 24000, or 48000.
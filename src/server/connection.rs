@@ -75,6 +75,10 @@ lazy_static::lazy_static! {
 lazy_static::lazy_static! {
     static ref WALLPAPER_REMOVER: Arc<Mutex<Option<WallPaperRemover>>> = Default::default();
 }
+#[cfg(target_os = "macos")]
+lazy_static::lazy_static! {
+    static ref SESSION_STATE_GUARD: Arc<Mutex<Option<crate::platform::macos::SessionStateGuard>>> = Default::default();
+}
 pub static CLICK_TIME: AtomicI64 = AtomicI64::new(0);
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 pub static MOUSE_MOVE_TIME: AtomicI64 = AtomicI64::new(0);
@@ -766,6 +770,8 @@ impl Connection {
             #[cfg(not(any(target_os = "android", target_os = "ios")))]
             try_stop_record_cursor_pos();
         }
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        flush_pending_mouse_buttons(id);
         conn.on_close("End", true).await;
         log::info!("#{} connection loop exited", id);
     }
@@ -862,6 +868,11 @@ impl Connection {
                 }
             }
         }
+        // The event tap is not bound to this thread like `BlockInput` on Windows.
+        #[cfg(target_os = "macos")]
+        if block_input_mode {
+            let _ = crate::platform::block_input(false);
+        }
         #[cfg(target_os = "linux")]
         clear_remapped_keycode();
         log::info!("Input thread exited");
@@ -3697,6 +3708,10 @@ extern "C" fn connection_shutdown_hook() {
     {
         *WALLPAPER_REMOVER.lock().unwrap() = None;
     }
+    #[cfg(target_os = "macos")]
+    {
+        *SESSION_STATE_GUARD.lock().unwrap() = None;
+    }
 }
 
 #[cfg(target_os = "macos")]
@@ -3782,6 +3797,13 @@ mod raii {
 
     impl AuthedConnID {
         pub fn new(id: i32, conn_type: AuthConnType) -> Self {
+            #[cfg(target_os = "macos")]
+            if conn_type == AuthConnType::Remote {
+                let mut guard = SESSION_STATE_GUARD.lock().unwrap();
+                if guard.is_none() {
+                    *guard = Some(crate::platform::macos::SessionStateGuard::new());
+                }
+            }
             AUTHED_CONNS.lock().unwrap().push((id, conn_type));
             Self::check_wake_lock();
             use std::sync::Once;
@@ -3835,6 +3857,10 @@ mod raii {
                 }
                 #[cfg(not(any(target_os = "android", target_os = "ios")))]
                 display_service::reset_resolutions();
+                #[cfg(target_os = "macos")]
+                {
+                    *SESSION_STATE_GUARD.lock().unwrap() = None;
+                }
                 #[cfg(windows)]
                 let _ = virtual_display_manager::reset_all();
                 #[cfg(target_os = "linux")]
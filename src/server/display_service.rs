@@ -293,7 +293,8 @@ pub(super) fn check_update_displays(all: &Vec<Display>) {
                 ((d.width() as f64) / scale).round() as usize,
                 (d.height() as f64 / scale).round() as usize,
             );
-            DisplayInfo {
+            #[allow(unused_mut)]
+            let mut info = DisplayInfo {
                 x: d.origin().0 as _,
                 y: d.origin().1 as _,
                 width: d.width() as _,
@@ -304,7 +305,13 @@ pub(super) fn check_update_displays(all: &Vec<Display>) {
                 original_resolution,
                 scale,
                 ..Default::default()
+            };
+            #[cfg(target_os = "macos")]
+            if let Ok(id) = info.name.parse::<u32>() {
+                info.safe_area_insets = Some(crate::platform::macos::safe_area_insets(id)).into();
+                info.safe_area_cropped = d.crop_top() > 0;
             }
+            info
         })
         .collect::<Vec<DisplayInfo>>();
     SYNC_DISPLAYS.lock().unwrap().check_changed(displays);
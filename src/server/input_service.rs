@@ -41,7 +41,7 @@ impl super::service::Reset for StateCursor {
     fn reset(&mut self) {
         *self = Default::default();
         crate::platform::reset_input_cache();
-        fix_key_down_timeout(true);
+        flush_pending_input();
     }
 }
 
@@ -410,6 +410,8 @@ lazy_static::lazy_static! {
         Arc::new(Mutex::new(Enigo::new()))
     };
     static ref KEYS_DOWN: Arc<Mutex<HashMap<KeysDown, Instant>>> = Default::default();
+    // Mouse buttons pressed by peers and not released yet, by connection id.
+    static ref MOUSE_BUTTONS_DOWN: Arc<Mutex<HashMap<i32, std::collections::HashSet<i32>>>> = Default::default();
     static ref LATEST_PEER_INPUT_CURSOR: Arc<Mutex<Input>> = Default::default();
     static ref LATEST_SYS_CURSOR_POS: Arc<Mutex<(Option<Instant>, (i32, i32))>> = Arc::new(Mutex::new((None, (INVALID_CURSOR_POS, INVALID_CURSOR_POS))));
 }
@@ -609,7 +611,67 @@ fn get_modifier_state(key: Key, en: &mut Enigo) -> bool {
     }
 }
 
+// Coalesce mouse moves of high polling rate clients, only the latest position of a frame is injected.
+#[cfg(target_os = "macos")]
+static INJECTION_COALESCING: AtomicBool = AtomicBool::new(false);
+#[cfg(target_os = "macos")]
+static INJECTION_FLUSH_RUNNING: AtomicBool = AtomicBool::new(false);
+#[cfg(target_os = "macos")]
+lazy_static::lazy_static! {
+    static ref PENDING_MOUSE_MOVE: Mutex<Option<(MouseEvent, i32)>> = Default::default();
+}
+
+#[cfg(target_os = "macos")]
+pub fn set_injection_coalescing(v: bool) {
+    INJECTION_COALESCING.store(v, Ordering::SeqCst);
+    if !v {
+        flush_pending_mouse_move();
+        return;
+    }
+    if INJECTION_FLUSH_RUNNING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    thread::spawn(|| {
+        let interval = crate::platform::macos::main_display_refresh_interval();
+        loop {
+            while INJECTION_COALESCING.load(Ordering::SeqCst) {
+                thread::sleep(interval);
+                flush_pending_mouse_move();
+            }
+            INJECTION_FLUSH_RUNNING.store(false, Ordering::SeqCst);
+            // Enabled again before RUNNING was cleared, no other flusher was started then.
+            if !INJECTION_COALESCING.load(Ordering::SeqCst)
+                || INJECTION_FLUSH_RUNNING.swap(true, Ordering::SeqCst)
+            {
+                break;
+            }
+        }
+        flush_pending_mouse_move();
+    });
+}
+
+#[cfg(target_os = "macos")]
+fn flush_pending_mouse_move() {
+    let pending = PENDING_MOUSE_MOVE.lock().unwrap().take();
+    if let Some((evt, conn)) = pending {
+        dispatch_mouse(&evt, conn);
+    }
+}
+
 pub fn handle_mouse(evt: &MouseEvent, conn: i32) {
+    #[cfg(target_os = "macos")]
+    if INJECTION_COALESCING.load(Ordering::SeqCst) {
+        if evt.mask & 0x7 == MOUSE_TYPE_MOVE {
+            *PENDING_MOUSE_MOVE.lock().unwrap() = Some((evt.clone(), conn));
+            return;
+        }
+        // Buttons and scrolls are never dropped, and must not overtake the pending move.
+        flush_pending_mouse_move();
+    }
+    dispatch_mouse(evt, conn);
+}
+
+fn dispatch_mouse(evt: &MouseEvent, conn: i32) {
     #[cfg(target_os = "macos")]
     if !is_server() {
         // having GUI, run main GUI thread, otherwise crash
@@ -726,6 +788,52 @@ fn release_record_key(record_key: KeysDown) {
     func();
 }
 
+/// Release the mouse buttons and keys pressed by peers but not released, e.g. the session ended during a drag.
+/// Physical input of the local user is not touched.
+pub fn flush_pending_input() {
+    let buttons: Vec<i32> = MOUSE_BUTTONS_DOWN
+        .lock()
+        .unwrap()
+        .drain()
+        .flat_map(|(_, buttons)| buttons)
+        .collect();
+    release_mouse_buttons(buttons);
+    fix_key_down_timeout(true);
+}
+
+/// Release the mouse buttons pressed by the connection `conn`, e.g. when it is closed during a drag.
+pub fn flush_pending_mouse_buttons(conn: i32) {
+    let buttons = MOUSE_BUTTONS_DOWN.lock().unwrap().remove(&conn);
+    if let Some(buttons) = buttons {
+        release_mouse_buttons(buttons.into_iter().collect());
+    }
+}
+
+fn release_mouse_buttons(buttons: Vec<i32>) {
+    if buttons.is_empty() {
+        return;
+    }
+    let func = move || {
+        let mut en = ENIGO.lock().unwrap();
+        for button in buttons {
+            let button = match button {
+                MOUSE_BUTTON_LEFT => MouseButton::Left,
+                MOUSE_BUTTON_RIGHT => MouseButton::Right,
+                MOUSE_BUTTON_WHEEL => MouseButton::Middle,
+                MOUSE_BUTTON_BACK => MouseButton::Back,
+                MOUSE_BUTTON_FORWARD => MouseButton::Forward,
+                _ => continue,
+            };
+            en.mouse_up(button);
+            log::debug!("Released pending {:?}", button);
+        }
+    };
+    #[cfg(target_os = "macos")]
+    QUEUE.exec_async(func);
+    #[cfg(not(target_os = "macos"))]
+    func();
+}
+
 fn fix_key_down_timeout(force: bool) {
     let key_down = KEYS_DOWN.lock().unwrap();
     if key_down.is_empty() {
@@ -925,6 +1033,26 @@ pub fn handle_mouse_(evt: &MouseEvent, conn: i32) {
             }
         }
     }
+    match evt_type {
+        MOUSE_TYPE_DOWN => {
+            MOUSE_BUTTONS_DOWN
+                .lock()
+                .unwrap()
+                .entry(conn)
+                .or_default()
+                .insert(buttons);
+        }
+        MOUSE_TYPE_UP => {
+            let mut lock = MOUSE_BUTTONS_DOWN.lock().unwrap();
+            if let Some(down) = lock.get_mut(&conn) {
+                down.remove(&buttons);
+                if down.is_empty() {
+                    lock.remove(&conn);
+                }
+            }
+        }
+        _ => {}
+    }
     match evt_type {
         MOUSE_TYPE_MOVE => {
             en.mouse_move_to(evt.x, evt.y);
@@ -1071,19 +1199,10 @@ pub async fn lock_screen() {
             handle_key(&key_event);
         });
     } else if #[cfg(target_os = "macos")] {
-        // CGSession -suspend not real lock screen, it is user switch
         std::thread::spawn(|| {
-            let mut key_event = KeyEvent::new();
-
-            key_event.set_chr('q' as _);
-            key_event.modifiers.push(ControlKey::Meta.into());
-            key_event.modifiers.push(ControlKey::Control.into());
-            key_event.mode = KeyboardMode::Legacy.into();
-
-            key_event.down = true;
-            handle_key(&key_event);
-            key_event.down = false;
-            handle_key(&key_event);
+            if let Err(e) = crate::platform::lock_screen() {
+                log::error!("Failed to lock screen: {}", e);
+            }
         });
     } else {
     crate::platform::lock_screen();
@@ -172,6 +172,7 @@ fn create_capturer(
     display: Display,
     _current: usize,
     _portable_service_running: bool,
+    _nv12: bool,
 ) -> ResultType<Box<dyn TraitCapturer>> {
     #[cfg(not(windows))]
     let c: Option<Box<dyn TraitCapturer>> = None;
@@ -206,8 +207,19 @@ fn create_capturer(
             #[cfg(not(windows))]
             {
                 log::debug!("Create capturer from scrap");
+                #[cfg(target_os = "macos")]
+                let capturer = Capturer::new_with_format(
+                    display,
+                    if _nv12 {
+                        scrap::PixelFormat::YCbCr420Video
+                    } else {
+                        scrap::PixelFormat::Argb8888
+                    },
+                );
+                #[cfg(not(target_os = "macos"))]
+                let capturer = Capturer::new(display);
                 return Ok(Box::new(
-                    Capturer::new(display).with_context(|| "Failed to create capturer")?,
+                    capturer.with_context(|| "Failed to create capturer")?,
                 ));
             }
         }
@@ -232,7 +244,7 @@ pub fn test_create_capturer(
                     )
                 } else {
                     let display = displays.remove(display_idx);
-                    match create_capturer(privacy_mode_id, display, display_idx, false) {
+                    match create_capturer(privacy_mode_id, display, display_idx, false, false) {
                         Ok(_) => return "".to_owned(),
                         Err(e) => e,
                     }
@@ -292,7 +304,12 @@ impl DerefMut for CapturerInfo {
     }
 }
 
-fn get_capturer(current: usize, portable_service_running: bool) -> ResultType<CapturerInfo> {
+// `nv12`: capture NV12 instead of BGRA if supported, macOS only.
+fn get_capturer(
+    current: usize,
+    portable_service_running: bool,
+    nv12: bool,
+) -> ResultType<CapturerInfo> {
     #[cfg(target_os = "linux")]
     {
         if !is_x11() {
@@ -300,6 +317,12 @@ fn get_capturer(current: usize, portable_service_running: bool) -> ResultType<Ca
         }
     }
 
+    #[cfg(target_os = "macos")]
+    if crate::platform::active_displays_count() == 0 {
+        // Displays may still be online but none is active, e.g. clamshell mode, capture yields nothing.
+        bail!("NoActiveDisplay: no active display to capture");
+    }
+
     let mut displays = Display::all()?;
     let ndisplay = displays.len();
     if ndisplay <= current {
@@ -369,6 +392,7 @@ fn get_capturer(current: usize, portable_service_running: bool) -> ResultType<Ca
         display,
         current,
         portable_service_running,
+        nv12,
     )?;
     Ok(CapturerInfo {
         origin,
@@ -406,7 +430,12 @@ fn run(vs: VideoService) -> ResultType<()> {
 
     let display_idx = vs.idx;
     let sp = vs.sp;
-    let mut c = get_capturer(display_idx, last_portable_service_running)?;
+    // The default of `scrap::set_capture_pixel_format()`, changed below to suit the encoder.
+    #[cfg(target_os = "macos")]
+    let nv12 = scrap::capture_pixel_format() == scrap::PixelFormat::YCbCr420Video;
+    #[cfg(not(target_os = "macos"))]
+    let nv12 = false;
+    let mut c = get_capturer(display_idx, last_portable_service_running, nv12)?;
     #[cfg(windows)]
     if !scrap::codec::enable_directx_capture() && !c.is_gdi() {
         log::info!("disable dxgi with option, fall back to gdi");
@@ -450,6 +479,19 @@ fn run(vs: VideoService) -> ResultType<()> {
             )?
         }
     };
+    #[cfg(target_os = "macos")]
+    {
+        // Hardware encoders take NV12 as captured, I444 needs the full chroma of BGRA.
+        let want_nv12 = if use_i444 {
+            false
+        } else {
+            nv12 || encoder.is_hardware()
+        };
+        if want_nv12 != nv12 {
+            log::info!("capture nv12: {} -> {}", nv12, want_nv12);
+            c = get_capturer(display_idx, last_portable_service_running, want_nv12)?;
+        }
+    }
     #[cfg(feature = "vram")]
     c.set_output_texture(encoder.input_texture());
     #[cfg(target_os = "android")]
@@ -486,6 +528,8 @@ fn run(vs: VideoService) -> ResultType<()> {
     let mut repeat_encode_counter = 0;
     let repeat_encode_max = 10;
     let mut encode_fail_counter = 0;
+    #[cfg(target_os = "macos")]
+    let mut capture_reconnecting = false;
 
     while sp.ok() {
         #[cfg(windows)]
@@ -564,6 +608,11 @@ fn run(vs: VideoService) -> ResultType<()> {
         let res = match c.frame(spf) {
             Ok(frame) => {
                 repeat_encode_counter = 0;
+                #[cfg(target_os = "macos")]
+                if capture_reconnecting {
+                    capture_reconnecting = false;
+                    send_capture_reconnecting(&sp, false);
+                }
                 if frame.valid() {
                     let frame = frame.to(encoder.yuvfmt(), &mut yuv, &mut mid_data)?;
                     let send_conn_ids = handle_one_frame(
@@ -634,6 +683,16 @@ fn run(vs: VideoService) -> ResultType<()> {
                     }
                 }
             }
+            // The capture stream stopped, the capturer restarts it in the following frames.
+            #[cfg(target_os = "macos")]
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                log::info!("{}", e);
+                // Once per recovery, the capturer may need several restarts.
+                if !capture_reconnecting {
+                    capture_reconnecting = true;
+                    send_capture_reconnecting(&sp, true);
+                }
+            }
             Err(err) => {
                 // This check may be redundant, but it is better to be safe.
                 // The previous check in `sp.is_option_true(OPTION_REFRESH)` block may be enough.
@@ -983,6 +1042,15 @@ fn start_uac_elevation_check() {
     });
 }
 
+#[cfg(target_os = "macos")]
+fn send_capture_reconnecting(sp: &GenericService, reconnecting: bool) {
+    let mut misc = Misc::new();
+    misc.set_capture_reconnecting(reconnecting);
+    let mut msg_out = Message::new();
+    msg_out.set_misc(misc);
+    sp.send(msg_out);
+}
+
 #[inline]
 fn try_broadcast_display_changed(
     sp: &GenericService,
@@ -751,6 +751,10 @@ impl sciter::host::HostHandler for UIHostHandler {
 #[cfg(not(target_os = "linux"))]
 fn get_sound_inputs() -> Vec<String> {
     let mut out = Vec::new();
+    #[cfg(target_os = "macos")]
+    if crate::platform::macos::can_capture_system_audio() {
+        out.push(crate::platform::macos::SYSTEM_SOUND_INPUT.to_owned());
+    }
     use cpal::traits::{DeviceTrait, HostTrait};
     let host = cpal::default_host();
     if let Ok(devices) = host.devices() {
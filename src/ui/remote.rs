@@ -86,7 +86,8 @@ impl InvokeUiSession for SciterHandler {
                     cd.hoty,
                     cd.width,
                     cd.height,
-                    &png[..]
+                    &png[..],
+                    cd.scale as f64
                 ),
             );
         }
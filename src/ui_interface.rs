@@ -346,6 +346,10 @@ pub fn get_sound_inputs() -> Vec<String> {
         std::thread::spawn(move || *cloned.lock().unwrap() = get_sound_inputs_())
             .join()
             .ok();
+        #[cfg(target_os = "macos")]
+        if crate::platform::macos::can_capture_system_audio() {
+            a.push(crate::platform::macos::SYSTEM_SOUND_INPUT.to_owned());
+        }
         for name in inputs.lock().unwrap().drain(..) {
             a.push(name);
         }